@@ -0,0 +1,432 @@
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+pub const EM_386: u16 = 3;
+pub const EM_MIPS: u16 = 8;
+pub const EM_PPC: u16 = 20;
+pub const EM_PPC64: u16 = 21;
+pub const EM_ARM: u16 = 40;
+pub const EM_X86_64: u16 = 62;
+pub const EM_AARCH64: u16 = 183;
+pub const EM_RISCV: u16 = 243;
+
+/// Architectures the host can run without emulation.
+#[cfg(target_arch = "x86_64")]
+const NATIVE: &[u16] = &[EM_X86_64, EM_386];
+#[cfg(target_arch = "x86")]
+const NATIVE: &[u16] = &[EM_386];
+#[cfg(target_arch = "aarch64")]
+const NATIVE: &[u16] = &[EM_AARCH64, EM_ARM];
+#[cfg(target_arch = "arm")]
+const NATIVE: &[u16] = &[EM_ARM];
+#[cfg(any(target_arch = "mips", target_arch = "mips64"))]
+const NATIVE: &[u16] = &[EM_MIPS];
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+const NATIVE: &[u16] = &[EM_RISCV];
+#[cfg(target_arch = "powerpc")]
+const NATIVE: &[u16] = &[EM_PPC];
+#[cfg(target_arch = "powerpc64")]
+const NATIVE: &[u16] = &[EM_PPC64, EM_PPC];
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "x86",
+    target_arch = "aarch64",
+    target_arch = "arm",
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "riscv32",
+    target_arch = "riscv64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64"
+)))]
+const NATIVE: &[u16] = &[];
+
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const PT_INTERP: u32 = 3;
+
+/// Header fields of an ELF executable that decide how to debug it.
+pub struct TargetInfo {
+    pub bits: u8,
+    pub big_endian: bool,
+    pub machine: u16,
+    /// Whether the executable is position independent.
+    pub pie: bool,
+    /// The dynamic loader, none for static executables.
+    pub interpreter: Option<PathBuf>,
+}
+
+/// Names of the architectures in `e_machine`, for error messages.
+fn machine_name(machine: u16) -> Option<&'static str> {
+    Some(match machine {
+        2 => "SPARC",
+        EM_386 => "i386",
+        EM_MIPS => "MIPS",
+        EM_PPC => "PowerPC",
+        EM_PPC64 => "PowerPC64",
+        22 => "S/390",
+        EM_ARM => "ARM",
+        EM_X86_64 => "x86-64",
+        EM_AARCH64 => "AArch64",
+        EM_RISCV => "RISC-V",
+        258 => "LoongArch",
+        _ => return None,
+    })
+}
+
+impl fmt::Display for TargetInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match machine_name(self.machine) {
+            Some(name) => write!(f, "{}", name)?,
+            None => write!(f, "machine {}", self.machine)?,
+        }
+        write!(
+            f,
+            " ({}-bit {}{})",
+            self.bits,
+            if self.big_endian {
+                "big endian"
+            } else {
+                "little endian"
+            },
+            if self.pie {
+                ", position independent"
+            } else {
+                ""
+            }
+        )
+    }
+}
+
+/// Reads integers in the byte order of the file.
+struct Reader<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl Reader<'_> {
+    fn uint(&self, offset: usize, size: usize) -> Option<u64> {
+        let bytes = self.data.get(offset..offset + size)?;
+        let fold = |x: u64, &byte: &u8| x << 8 | byte as u64;
+        Some(if self.big_endian {
+            bytes.iter().fold(0, fold)
+        } else {
+            bytes.iter().rev().fold(0, fold)
+        })
+    }
+}
+
+impl TargetInfo {
+    /// Whether the executable runs on this machine without emulation.
+    pub fn is_native(&self) -> bool {
+        NATIVE.contains(&self.machine)
+    }
+
+    /// The architecture as qemu user mode emulation names it, `qemu-NAME`
+    /// runs the executable.
+    pub fn qemu_name(&self) -> Option<&'static str> {
+        Some(match (self.machine, self.bits, self.big_endian) {
+            (EM_386, ..) => "i386",
+            (EM_X86_64, ..) => "x86_64",
+            (EM_ARM, _, false) => "arm",
+            (EM_ARM, _, true) => "armeb",
+            (EM_AARCH64, _, false) => "aarch64",
+            (EM_AARCH64, _, true) => "aarch64_be",
+            (EM_MIPS, 32, false) => "mipsel",
+            (EM_MIPS, 32, true) => "mips",
+            (EM_MIPS, _, false) => "mips64el",
+            (EM_MIPS, _, true) => "mips64",
+            (EM_PPC, ..) => "ppc",
+            (EM_PPC64, _, false) => "ppc64le",
+            (EM_PPC64, _, true) => "ppc64",
+            (EM_RISCV, 32, _) => "riscv32",
+            (EM_RISCV, ..) => "riscv64",
+            _ => return None,
+        })
+    }
+
+    pub fn read(path: &Path) -> std::io::Result<TargetInfo> {
+        let invalid = |message: String| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), message),
+            )
+        };
+
+        let mut file = File::open(path)?;
+        let mut header = Vec::with_capacity(64);
+        (&mut file).take(64).read_to_end(&mut header)?;
+
+        if header.starts_with(b"#!") {
+            return Err(invalid(
+                "a script rather than an ELF executable, pass its interpreter as the program instead"
+                    .to_string(),
+            ));
+        }
+        if !header.starts_with(b"\x7fELF") {
+            return Err(invalid("not an ELF executable".to_string()));
+        }
+        let truncated = || invalid("truncated ELF header".to_string());
+        let bits = match header.get(4) {
+            Some(1) => 32,
+            Some(2) => 64,
+            Some(class) => return Err(invalid(format!("invalid ELF class {}", class))),
+            None => return Err(truncated()),
+        };
+        let big_endian = match header.get(5) {
+            Some(1) => false,
+            Some(2) => true,
+            Some(data) => return Err(invalid(format!("invalid ELF data encoding {}", data))),
+            None => return Err(truncated()),
+        };
+
+        let reader = Reader {
+            data: &header,
+            big_endian,
+        };
+        let word = if bits == 32 { 4 } else { 8 };
+        let kind = reader.uint(16, 2).ok_or_else(truncated)? as u16;
+        let machine = reader.uint(18, 2).ok_or_else(truncated)? as u16;
+        let phoff = reader.uint(24 + word, word).ok_or_else(truncated)?;
+        let phentsize = reader.uint(30 + 3 * word, 2).ok_or_else(truncated)?;
+        let phnum = reader.uint(32 + 3 * word, 2).ok_or_else(truncated)?;
+
+        let pie = match kind {
+            ET_EXEC => false,
+            ET_DYN => true,
+            1 => {
+                return Err(invalid(
+                    "a relocatable object, not an executable".to_string(),
+                ))
+            }
+            4 => return Err(invalid("a core dump, not an executable".to_string())),
+            kind => return Err(invalid(format!("unknown ELF type {}", kind))),
+        };
+
+        let mut interpreter = None;
+        for i in 0..phnum {
+            let mut entry = vec![0; phentsize as usize];
+            file.seek(SeekFrom::Start(phoff + i * phentsize))?;
+            file.read_exact(&mut entry)
+                .map_err(|_| invalid("truncated program headers".to_string()))?;
+            let entry = Reader {
+                data: &entry,
+                big_endian,
+            };
+            let truncated = || invalid("truncated program headers".to_string());
+            if entry.uint(0, 4).ok_or_else(truncated)? != PT_INTERP as u64 {
+                continue;
+            }
+
+            let (offset, size) = if bits == 32 {
+                (entry.uint(4, 4), entry.uint(16, 4))
+            } else {
+                (entry.uint(8, 8), entry.uint(32, 8))
+            };
+            let size = size.ok_or_else(truncated)?;
+            if size > libc::PATH_MAX as u64 {
+                return Err(invalid("interpreter path too long".to_string()));
+            }
+            let mut name = vec![0; size as usize];
+            file.seek(SeekFrom::Start(offset.ok_or_else(truncated)?))?;
+            file.read_exact(&mut name)
+                .map_err(|_| invalid("truncated interpreter path".to_string()))?;
+            let name = name.split(|&x| x == 0).next().unwrap_or_default();
+            interpreter = Some(PathBuf::from(OsStr::from_bytes(name)));
+        }
+
+        Ok(TargetInfo {
+            bits,
+            big_endian,
+            machine,
+            pie,
+            interpreter,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put(data: &mut [u8], offset: usize, size: usize, value: u64, big_endian: bool) {
+        for i in 0..size {
+            let shift = if big_endian { size - 1 - i } else { i } * 8;
+            data[offset + i] = (value >> shift) as u8;
+        }
+    }
+
+    /// Builds an executable header, followed by a PT_INTERP program header
+    /// and the interpreter path if there is one.
+    fn executable(
+        bits: u8,
+        big_endian: bool,
+        kind: u16,
+        machine: u16,
+        interpreter: Option<&str>,
+    ) -> Vec<u8> {
+        let word = if bits == 32 { 4 } else { 8 };
+        let (header_size, entry_size) = if bits == 32 { (52, 32) } else { (64, 56) };
+        let mut data = vec![0; header_size];
+        data[..4].copy_from_slice(b"\x7fELF");
+        data[4] = if bits == 32 { 1 } else { 2 };
+        data[5] = if big_endian { 2 } else { 1 };
+        data[6] = 1;
+        put(&mut data, 16, 2, kind as u64, big_endian);
+        put(&mut data, 18, 2, machine as u64, big_endian);
+
+        if let Some(interpreter) = interpreter {
+            put(&mut data, 24 + word, word, header_size as u64, big_endian);
+            put(&mut data, 30 + 3 * word, 2, entry_size as u64, big_endian);
+            put(&mut data, 32 + 3 * word, 2, 1, big_endian);
+
+            let mut entry = vec![0; entry_size];
+            let offset = (header_size + entry_size) as u64;
+            let size = interpreter.len() as u64 + 1;
+            put(&mut entry, 0, 4, PT_INTERP as u64, big_endian);
+            if bits == 32 {
+                put(&mut entry, 4, 4, offset, big_endian);
+                put(&mut entry, 16, 4, size, big_endian);
+            } else {
+                put(&mut entry, 8, 8, offset, big_endian);
+                put(&mut entry, 32, 8, size, big_endian);
+            }
+            data.extend(entry);
+            data.extend(interpreter.as_bytes());
+            data.push(0);
+        }
+        data
+    }
+
+    fn read(name: &str, data: &[u8]) -> std::io::Result<TargetInfo> {
+        let path = std::env::temp_dir().join(format!("netpwn-elf-{}-{}", std::process::id(), name));
+        std::fs::write(&path, data).unwrap();
+        let info = TargetInfo::read(&path);
+        std::fs::remove_file(&path).unwrap();
+        info
+    }
+
+    fn error(name: &str, data: &[u8]) -> String {
+        match read(name, data) {
+            Ok(info) => panic!("read {} as {}", name, info),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn reads_dynamic_amd64() {
+        let data = executable(
+            64,
+            false,
+            ET_DYN,
+            EM_X86_64,
+            Some("/lib64/ld-linux-x86-64.so.2"),
+        );
+        let info = read("amd64", &data).unwrap();
+        assert_eq!((info.bits, info.big_endian), (64, false));
+        assert_eq!(info.machine, EM_X86_64);
+        assert!(info.pie);
+        assert_eq!(
+            info.interpreter,
+            Some(PathBuf::from("/lib64/ld-linux-x86-64.so.2"))
+        );
+        assert_eq!(info.qemu_name(), Some("x86_64"));
+    }
+
+    #[test]
+    fn reads_static_i386() {
+        let info = read("i386", &executable(32, false, ET_EXEC, EM_386, None)).unwrap();
+        assert_eq!((info.bits, info.machine), (32, EM_386));
+        assert!(!info.pie);
+        assert_eq!(info.interpreter, None);
+        assert_eq!(info.qemu_name(), Some("i386"));
+    }
+
+    #[test]
+    fn reads_x32() {
+        let data = executable(
+            32,
+            false,
+            ET_EXEC,
+            EM_X86_64,
+            Some("/libx32/ld-linux-x32.so.2"),
+        );
+        let info = read("x32", &data).unwrap();
+        assert_eq!((info.bits, info.machine), (32, EM_X86_64));
+        assert_eq!(
+            info.interpreter,
+            Some(PathBuf::from("/libx32/ld-linux-x32.so.2"))
+        );
+    }
+
+    #[test]
+    fn reads_big_endian() {
+        let data = executable(32, true, ET_EXEC, EM_MIPS, Some("/lib/ld.so.1"));
+        let info = read("mips", &data).unwrap();
+        assert!(info.big_endian);
+        assert_eq!(info.machine, EM_MIPS);
+        assert_eq!(info.interpreter, Some(PathBuf::from("/lib/ld.so.1")));
+        assert_eq!(info.qemu_name(), Some("mips"));
+
+        let info = read("mipsel", &executable(32, false, ET_EXEC, EM_MIPS, None)).unwrap();
+        assert_eq!(info.qemu_name(), Some("mipsel"));
+
+        let cases = [
+            (EM_AARCH64, 64, "aarch64_be"),
+            (EM_ARM, 32, "armeb"),
+            (EM_MIPS, 64, "mips64"),
+            (EM_PPC64, 64, "ppc64"),
+        ];
+        for &(machine, bits, name) in &cases {
+            let data = executable(bits, true, ET_DYN, machine, None);
+            let info = read(name, &data).unwrap();
+            assert_eq!((info.machine, info.bits), (machine, bits));
+            assert_eq!(info.qemu_name(), Some(name));
+        }
+    }
+
+    #[test]
+    fn reads_the_test_binary() {
+        let info = TargetInfo::read(&std::env::current_exe().unwrap()).unwrap();
+        assert!(info.is_native());
+    }
+
+    #[test]
+    fn rejects_scripts() {
+        assert!(error("script", b"#!/bin/sh\necho hi\n").contains("script"));
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(error("text", b"hello").contains("not an ELF executable"));
+        assert!(error("empty", b"").contains("not an ELF executable"));
+        let data = executable(64, false, 1, EM_X86_64, None);
+        assert!(error("object", &data).contains("relocatable object"));
+        let data = executable(64, false, 4, EM_X86_64, None);
+        assert!(error("core", &data).contains("core dump"));
+    }
+
+    #[test]
+    fn rejects_truncated_headers() {
+        assert!(error("magic", b"\x7fELF").contains("truncated ELF header"));
+        assert!(error("class", b"\x7fELF\x02").contains("truncated ELF header"));
+        let data = executable(64, false, ET_DYN, EM_X86_64, None);
+        assert!(error("header", &data[..40]).contains("truncated ELF header"));
+
+        let data = executable(64, false, ET_DYN, EM_X86_64, Some("/lib/ld.so"));
+        assert!(error("program-headers", &data[..80]).contains("truncated program headers"));
+        let data = &data[..data.len() - 4];
+        assert!(error("interpreter", data).contains("truncated interpreter path"));
+    }
+
+    #[test]
+    fn rejects_invalid_identification() {
+        assert!(error("bad-class", b"\x7fELF\x03\x01").contains("invalid ELF class 3"));
+        assert!(error("bad-data", b"\x7fELF\x02\x03").contains("invalid ELF data encoding 3"));
+    }
+}
@@ -0,0 +1,129 @@
+use std::ffi::{CString, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+use crate::payload;
+
+extern "C" {
+    static mut environ: *const *const libc::c_char;
+}
+
+/// Variables gdb sets for the inferior from its own terminal, which shift the
+/// stack compared to a run without gdb.
+pub const GDB_VARIABLES: &[&str] = &["LINES", "COLUMNS"];
+
+/// A NUL terminated array of pointers into the strings it owns, for use
+/// as `environ`.
+struct Envp {
+    _vars: Vec<CString>,
+    pointers: Vec<usize>,
+}
+
+impl Envp {
+    fn as_ptr(&self) -> *const *const libc::c_char {
+        self.pointers.as_ptr() as *const *const libc::c_char
+    }
+}
+
+/// The environment the target starts with.
+#[derive(Clone, Default)]
+pub struct Environment {
+    /// Starts from nothing instead of netpwn's own environment.
+    pub clean: bool,
+    /// Set on top, in this order.
+    pub vars: Vec<(OsString, OsString)>,
+    /// Removed at the end.
+    pub unset: Vec<OsString>,
+}
+
+impl Environment {
+    /// Whether the target gets exactly what netpwn has itself.
+    pub fn is_inherited(&self) -> bool {
+        !self.clean && self.vars.is_empty() && self.unset.is_empty()
+    }
+
+    /// The variables in the order the target sees them. Set ones follow the
+    /// inherited ones in the order given, even if they replace one of them.
+    pub fn resolve(&self) -> Vec<(OsString, OsString)> {
+        let mut vars: Vec<(OsString, OsString)> = if self.clean {
+            Vec::new()
+        } else {
+            std::env::vars_os().collect()
+        };
+        for (key, value) in &self.vars {
+            vars.retain(|x| &x.0 != key);
+            vars.push((key.clone(), value.clone()));
+        }
+        vars.retain(|x| !self.unset.contains(&x.0));
+        vars
+    }
+
+    /// Arguments that make the `exec-wrapper` subcommand set up the same
+    /// environment on top of the one it inherits, so that only what was asked
+    /// for shows up in its command line. Values are escaped as gdb reads the
+    /// wrapper as a single line.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.clean {
+            args.push("--clean-env".to_string());
+        }
+        for (key, value) in &self.vars {
+            args.push(format!(
+                "--env={}={}",
+                payload::encode(key),
+                payload::encode(value)
+            ));
+        }
+        for key in &self.unset {
+            args.push(format!("--unset={}", payload::encode(key)));
+        }
+        args
+    }
+
+    /// Replaces the environment of the current process, keeping the order.
+    pub fn apply(&self) {
+        let vars = self.resolve();
+        for (key, _) in std::env::vars_os() {
+            std::env::remove_var(key);
+        }
+        for (key, value) in vars {
+            std::env::set_var(key, value);
+        }
+    }
+
+    /// Hands the environment to the command. std sorts the variables as soon
+    /// as any of them is changed, so the command gets them through `environ`
+    /// right before its exec instead.
+    pub fn attach(&self, cmd: &mut Command) -> std::io::Result<()> {
+        if self.is_inherited() {
+            return Ok(());
+        }
+
+        let vars = self
+            .resolve()
+            .into_iter()
+            .map(|(key, value)| {
+                let mut var = key.as_bytes().to_vec();
+                var.push(b'=');
+                var.extend(value.as_bytes());
+                CString::new(var).map_err(std::io::Error::from)
+            })
+            .collect::<std::io::Result<Vec<CString>>>()?;
+        let mut pointers: Vec<usize> = vars.iter().map(|x| x.as_ptr() as usize).collect();
+        pointers.push(0);
+        let envp = Envp {
+            _vars: vars,
+            pointers,
+        };
+
+        unsafe {
+            cmd.pre_exec(move || {
+                environ = envp.as_ptr();
+                Ok(())
+            });
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+use crate::sys::check;
+use std::os::unix::io::RawFd;
+
+/// Not in libc yet, new syscalls have the same number on every architecture.
+const SYS_CLOSE_RANGE: libc::c_long = 436;
+const CLOSE_RANGE_CLOEXEC: libc::c_uint = 1 << 2;
+
+fn set_cloexec(fd: RawFd, cloexec: bool) -> std::io::Result<()> {
+    let flags = check(unsafe { libc::fcntl(fd, libc::F_GETFD) })?;
+    let flags = if cloexec {
+        flags | libc::FD_CLOEXEC
+    } else {
+        flags & !libc::FD_CLOEXEC
+    };
+    check(unsafe { libc::fcntl(fd, libc::F_SETFD, flags) })?;
+    Ok(())
+}
+
+fn set_cloexec_range(first: RawFd, last: RawFd) -> std::io::Result<()> {
+    let ret = unsafe {
+        libc::syscall(
+            SYS_CLOSE_RANGE,
+            first as libc::c_uint,
+            last as libc::c_uint,
+            CLOSE_RANGE_CLOEXEC,
+        )
+    };
+    if ret == 0 {
+        return Ok(());
+    }
+    let error = std::io::Error::last_os_error();
+    if error.raw_os_error() != Some(libc::ENOSYS) && error.raw_os_error() != Some(libc::EINVAL) {
+        return Err(error);
+    }
+
+    // Kernels before 5.11, every descriptor up to the limit has to be tried.
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    check(unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) })?;
+    let last = last.min(limit.rlim_cur.min(1 << 20) as RawFd - 1);
+    for fd in first..=last {
+        match set_cloexec(fd, true) {
+            Err(e) if e.raw_os_error() != Some(libc::EBADF) => return Err(e),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Makes sure that only stdio and the descriptors in `keep`, which has to be
+/// sorted, survive the next exec. Fork-safe, see [`crate::sys`], and keeps
+/// the pipe std reports exec errors through intact when used there.
+pub fn keep_only(keep: &[RawFd]) -> std::io::Result<()> {
+    let mut first = 3;
+    for &fd in keep.iter().filter(|&&x| x > 2) {
+        if fd > first {
+            set_cloexec_range(first, fd - 1)?;
+        }
+        set_cloexec(fd, false)?;
+        first = fd + 1;
+    }
+    set_cloexec_range(first, RawFd::MAX)
+}
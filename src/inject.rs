@@ -0,0 +1,401 @@
+use crate::elf::{self, TargetInfo};
+use std::os::unix::io::RawFd;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, ExitStatus};
+
+const PR_SET_PTRACER: u64 = 0x5961_6d61;
+
+/// A target we started for gdb to attach to.
+pub struct Inferior {
+    /// The process we spawned, the exec wrapper if there is one.
+    pub child: Child,
+    /// The target itself, which the wrapper might have forked off.
+    pub pid: libc::pid_t,
+}
+
+impl Inferior {
+    pub fn kill(mut self) {
+        unsafe { libc::kill(self.pid, libc::SIGKILL) };
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+
+    /// Collects the exit status once the debugger is gone, killing the target
+    /// first if the debugger left it running. The exec wrapper passes on the
+    /// status of a target it forked.
+    pub fn finish(mut self) -> std::io::Result<ExitStatus> {
+        if let Some(status) = self.child.try_wait()? {
+            return Ok(status);
+        }
+        unsafe { libc::kill(self.pid, libc::SIGKILL) };
+        let _ = self.child.kill();
+        self.child.wait()
+    }
+}
+
+/// Whether the stdio of the executable can be redirected without gdb's help.
+/// Only amd64 hosts do so, for amd64 and i386 executables. Other native ones
+/// are left to gdb's `compile code`, foreign ones get their stdio from qemu.
+pub fn supports(info: &TargetInfo) -> bool {
+    cfg!(target_arch = "x86_64")
+        && matches!(
+            (info.machine, info.bits),
+            (elf::EM_X86_64, 64) | (elf::EM_386, 32)
+        )
+}
+
+fn ptrace(
+    request: libc::c_uint,
+    pid: libc::pid_t,
+    addr: usize,
+    data: usize,
+) -> std::io::Result<libc::c_long> {
+    // PEEK requests return data, only errno tells errors apart.
+    unsafe { *libc::__errno_location() = 0 };
+    let ret = unsafe {
+        libc::ptrace(
+            request,
+            pid,
+            addr as *mut libc::c_void,
+            data as *mut libc::c_void,
+        )
+    };
+    let error = std::io::Error::last_os_error();
+    if ret == -1 && error.raw_os_error() != Some(0) {
+        return Err(error);
+    }
+    Ok(ret)
+}
+
+fn exited(status: libc::c_int) -> std::io::Error {
+    let how = if unsafe { libc::WIFSIGNALED(status) } {
+        format!("was killed by signal {}", unsafe { libc::WTERMSIG(status) })
+    } else {
+        format!("exited with {}", unsafe { libc::WEXITSTATUS(status) })
+    };
+    std::io::Error::other(format!("the target {} before it started", how))
+}
+
+fn sigchld() -> libc::sigset_t {
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGCHLD);
+        set
+    }
+}
+
+/// Waits for the next stop of any of `pids`. They might not be our children,
+/// so other children of ours must not be reaped on the way.
+fn wait(pids: &[libc::pid_t]) -> std::io::Result<(libc::pid_t, libc::c_int)> {
+    let mut status = 0;
+    if let [pid] = *pids {
+        loop {
+            if unsafe { libc::waitpid(pid, &mut status, libc::__WALL) } > 0 {
+                return Ok((pid, status));
+            }
+            let err = std::io::Error::last_os_error();
+            if err.kind() != std::io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+
+    let set = sigchld();
+    loop {
+        for &pid in pids {
+            match unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG | libc::__WALL) } {
+                0 => (),
+                ret if ret > 0 => return Ok((pid, status)),
+                _ => return Err(std::io::Error::last_os_error()),
+            }
+        }
+        // SIGCHLD is blocked, so it stays pending until we take it. Another
+        // thread might get to it first though, hence the timeout.
+        let timeout = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 100_000_000,
+        };
+        unsafe { libc::sigtimedwait(&set, std::ptr::null_mut(), &timeout) };
+    }
+}
+
+/// Keeps SIGCHLD pending for `wait` to pick up.
+struct BlockSigchld(libc::sigset_t);
+
+impl BlockSigchld {
+    fn new() -> BlockSigchld {
+        unsafe {
+            let mut old: libc::sigset_t = std::mem::zeroed();
+            libc::pthread_sigmask(libc::SIG_BLOCK, &sigchld(), &mut old);
+            BlockSigchld(old)
+        }
+    }
+}
+
+impl Drop for BlockSigchld {
+    fn drop(&mut self) {
+        unsafe { libc::pthread_sigmask(libc::SIG_SETMASK, &self.0, std::ptr::null_mut()) };
+    }
+}
+
+/// Spawns `cmd` and stops the target on its very first instruction, with
+/// `fd` moved onto its stdio if given. Once this returns, the target waits for a
+/// debugger to attach and continue it.
+///
+/// With `wrapped` set, `cmd` runs the exec wrapper and the target is what
+/// it executes in the end, possibly in a process forked on the way.
+pub fn spawn(cmd: &mut Command, fd: Option<RawFd>, wrapped: bool) -> std::io::Result<Inferior> {
+    let _blocked = BlockSigchld::new();
+    unsafe {
+        cmd.pre_exec(|| ptrace(libc::PTRACE_TRACEME, 0, 0, 0).map(|_| ()));
+    }
+    let mut child = cmd.spawn()?;
+
+    match start(child.id() as libc::pid_t, fd, wrapped) {
+        Ok(pid) => Ok(Inferior { child, pid }),
+        Err(e) => {
+            let _ = child.kill();
+            let _ = child.wait();
+            Err(e)
+        }
+    }
+}
+
+fn start(pid: libc::pid_t, fd: Option<RawFd>, wrapped: bool) -> std::io::Result<libc::pid_t> {
+    // Exec reports a SIGTRAP to begin with.
+    let (_, status) = wait(&[pid])?;
+    if !unsafe { libc::WIFSTOPPED(status) } {
+        return Err(exited(status));
+    }
+
+    let mut tracees = vec![pid];
+    if wrapped {
+        let options = libc::PTRACE_O_TRACEEXEC
+            | libc::PTRACE_O_TRACEFORK
+            | libc::PTRACE_O_TRACEVFORK
+            | libc::PTRACE_O_TRACECLONE;
+        ptrace(libc::PTRACE_SETOPTIONS, pid, 0, options as usize)?;
+        ptrace(libc::PTRACE_CONT, pid, 0, 0)?;
+    }
+
+    // Processes of the wrapper that forked are done setting things up, only
+    // their children are followed to the exec of the target.
+    let target = loop {
+        if !wrapped {
+            break pid;
+        }
+        let (tracee, status) = wait(&tracees)?;
+        if !unsafe { libc::WIFSTOPPED(status) } {
+            tracees.retain(|&x| x != tracee);
+            if tracees.is_empty() {
+                return Err(exited(status));
+            }
+            continue;
+        }
+
+        match status >> 16 {
+            libc::PTRACE_EVENT_EXEC => {
+                // The exec is not done yet and would clobber the registers we
+                // set, a signal stops the target once it is.
+                unsafe { libc::kill(tracee, libc::SIGSTOP) };
+                ptrace(libc::PTRACE_CONT, tracee, 0, 0)?;
+                let (_, status) = wait(&[tracee])?;
+                if !unsafe { libc::WIFSTOPPED(status) } {
+                    return Err(exited(status));
+                }
+                break tracee;
+            }
+            libc::PTRACE_EVENT_FORK | libc::PTRACE_EVENT_VFORK | libc::PTRACE_EVENT_CLONE => {
+                let mut forked: libc::c_ulong = 0;
+                ptrace(
+                    libc::PTRACE_GETEVENTMSG,
+                    tracee,
+                    0,
+                    &mut forked as *mut _ as usize,
+                )?;
+                tracees.retain(|&x| x != tracee);
+                tracees.push(forked as libc::pid_t);
+                ptrace(libc::PTRACE_DETACH, tracee, 0, 0)?;
+            }
+            _ => {
+                // Forked processes start out with a SIGSTOP of their own.
+                let signal = match unsafe { libc::WSTOPSIG(status) } {
+                    libc::SIGSTOP | libc::SIGTRAP => 0,
+                    signal => signal,
+                };
+                ptrace(libc::PTRACE_CONT, tracee, 0, signal as usize)?;
+            }
+        }
+    };
+
+    redirect(target, fd)?;
+    ptrace(libc::PTRACE_DETACH, target, 0, libc::SIGSTOP as usize)?;
+    Ok(target)
+}
+
+/// Makes the stopped `pid` run dup2 onto stdio and close `fd`, if given, and
+/// allow us and thus the debuggers we start to attach, then puts its registers
+/// and code back as they were.
+#[cfg(target_arch = "x86_64")]
+fn redirect(pid: libc::pid_t, fd: Option<RawFd>) -> std::io::Result<()> {
+    let mut saved: libc::user_regs_struct = unsafe { std::mem::zeroed() };
+    ptrace(libc::PTRACE_GETREGS, pid, 0, &mut saved as *mut _ as usize)?;
+
+    // A 32-bit process, on the code segment the kernel gives those.
+    let compat = saved.cs == 0x23;
+    let (fd, calls) = match fd {
+        Some(fd) => (fd as u64, 4),
+        None => (0, 0),
+    };
+    let tracer = unsafe { libc::getpid() } as u64;
+    let (instruction, all, prctl) = if compat {
+        // int $0x80
+        let calls = [(63, fd, 0), (63, fd, 1), (63, fd, 2), (6, fd, 0)];
+        (0x80cd, calls, (172, PR_SET_PTRACER, tracer))
+    } else {
+        // syscall
+        let calls = [(33, fd, 0), (33, fd, 1), (33, fd, 2), (3, fd, 0)];
+        (0x050f, calls, (157, PR_SET_PTRACER, tracer))
+    };
+
+    let ip = saved.rip as usize;
+    let text = ptrace(libc::PTRACE_PEEKTEXT, pid, ip, 0)? as u64;
+    ptrace(
+        libc::PTRACE_POKETEXT,
+        pid,
+        ip,
+        (text & !0xffff | instruction) as usize,
+    )?;
+
+    let syscall = |(nr, first, second): (u64, u64, u64)| -> std::io::Result<i64> {
+        let mut regs = saved;
+        regs.rax = nr;
+        // Keeps the kernel from treating this as a syscall to restart.
+        regs.orig_rax = u64::MAX;
+        if compat {
+            regs.rbx = first;
+            regs.rcx = second;
+        } else {
+            regs.rdi = first;
+            regs.rsi = second;
+        }
+        ptrace(libc::PTRACE_SETREGS, pid, 0, &regs as *const _ as usize)?;
+        ptrace(libc::PTRACE_SINGLESTEP, pid, 0, 0)?;
+        let (_, status) = wait(&[pid])?;
+        if !unsafe { libc::WIFSTOPPED(status) } {
+            return Err(exited(status));
+        }
+        let signal = unsafe { libc::WSTOPSIG(status) };
+        if signal != libc::SIGTRAP {
+            return Err(std::io::Error::other(format!(
+                "the target got signal {} while redirecting stdio",
+                signal
+            )));
+        }
+        ptrace(libc::PTRACE_GETREGS, pid, 0, &mut regs as *mut _ as usize)?;
+        Ok(if compat {
+            regs.rax as i32 as i64
+        } else {
+            regs.rax as i64
+        })
+    };
+
+    let result = all[..calls]
+        .iter()
+        .try_for_each(|&call| match syscall(call)? {
+            ret if ret < 0 => Err(std::io::Error::from_raw_os_error(-ret as i32)),
+            _ => Ok(()),
+        });
+    // Lets gdb and gdbserver attach when Yama restricts ptrace to ancestors,
+    // they are our descendants but not the target's. Kernels without Yama
+    // reject this, as does a target in a PID namespace of its own, which is in
+    // a user namespace of ours that lets us in anyway.
+    let result = result.and_then(|_| syscall(prctl).map(|_| ()));
+
+    ptrace(libc::PTRACE_POKETEXT, pid, ip, text as usize)?;
+    ptrace(libc::PTRACE_SETREGS, pid, 0, &saved as *const _ as usize)?;
+    result
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn redirect(_: libc::pid_t, fd: Option<RawFd>) -> std::io::Result<()> {
+    if fd.is_none() {
+        return Ok(());
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "redirecting stdio with ptrace is only supported on amd64 hosts",
+    ))
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use std::io::prelude::*;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::time::Duration;
+
+    /// Where the target finds its end of the socket, kept open across exec.
+    const FD: RawFd = 10;
+
+    const SCRIPT: &str =
+        "echo out; echo err >&2; read line; echo \"got $line\"; test -e /proc/$$/fd/10 || echo closed";
+
+    /// Starts `cmd` with stdio redirected to a socket and returns what the
+    /// target wrote to it.
+    fn run(cmd: &mut Command, wrapped: bool) -> String {
+        let (mut ours, theirs) = UnixStream::pair().unwrap();
+        let fd = theirs.as_raw_fd();
+        unsafe {
+            cmd.pre_exec(move || match libc::dup2(fd, FD) {
+                -1 => Err(std::io::Error::last_os_error()),
+                _ => Ok(()),
+            });
+        }
+        let mut inferior = spawn(cmd, Some(FD), wrapped).unwrap();
+        drop(theirs);
+
+        // Nobody attaches, the target just has to go on.
+        unsafe { libc::kill(inferior.pid, libc::SIGCONT) };
+        ours.set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        ours.write_all(b"ping\n").unwrap();
+        let mut output = String::new();
+        ours.read_to_string(&mut output).unwrap();
+        assert!(inferior.child.wait().unwrap().success());
+        output
+    }
+
+    #[test]
+    fn redirects_stdio() {
+        let output = run(Command::new("/bin/sh").args(["-c", SCRIPT]), false);
+        assert_eq!(output, "out\nerr\ngot ping\nclosed\n");
+    }
+
+    #[test]
+    fn follows_the_wrapper_to_the_target() {
+        let mut cmd = Command::new("/usr/bin/env");
+        cmd.args(["/bin/sh", "-c", SCRIPT]);
+        assert_eq!(run(&mut cmd, true), "out\nerr\ngot ping\nclosed\n");
+    }
+
+    #[test]
+    fn follows_the_wrapper_into_forks() {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(["-c", "/bin/sh -c \"$0\"; exit $?", SCRIPT]);
+        assert_eq!(run(&mut cmd, true), "out\nerr\ngot ping\nclosed\n");
+    }
+
+    #[test]
+    fn reports_wrappers_that_exit_early() {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(["-c", "exit 3"]);
+        let err = spawn(&mut cmd, None, true).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "the target exited with 3 before it started"
+        );
+    }
+}
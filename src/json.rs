@@ -0,0 +1,194 @@
+pub fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.peek().is_some_and(|c| c.is_whitespace()) {
+            self.chars.next();
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        self.skip_whitespace();
+        match self.chars.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(format!("expected `{}`, found `{}`", expected, c)),
+            None => Err(format!("expected `{}`, found end of input", expected)),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut out = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(out),
+                Some('\\') => match self.chars.next() {
+                    Some('"') => out.push('"'),
+                    Some('\\') => out.push('\\'),
+                    Some('/') => out.push('/'),
+                    Some('b') => out.push('\u{8}'),
+                    Some('f') => out.push('\u{c}'),
+                    Some('n') => out.push('\n'),
+                    Some('r') => out.push('\r'),
+                    Some('t') => out.push('\t'),
+                    Some('u') => {
+                        let code: String = self.chars.by_ref().take(4).collect();
+                        let code = u32::from_str_radix(&code, 16)
+                            .map_err(|_| format!("invalid unicode escape: {}", code))?;
+                        out.push(std::char::from_u32(code).unwrap_or('\u{fffd}'));
+                    }
+                    c => return Err(format!("invalid escape sequence: {:?}", c)),
+                },
+                Some(c) => out.push(c),
+                None => return Err("unterminated string".to_string()),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+        match self.chars.peek() {
+            Some('"') => Ok(Value::String(self.string()?)),
+            Some(c) if c.is_ascii_alphanumeric() || *c == '-' => {
+                let mut token = String::new();
+                while let Some(&c) = self.chars.peek() {
+                    if !(c.is_ascii_alphanumeric() || "+-.".contains(c)) {
+                        break;
+                    }
+                    token.push(c);
+                    self.chars.next();
+                }
+                match token.as_str() {
+                    "null" => Ok(Value::Null),
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    _ => token
+                        .parse()
+                        .map(Value::Number)
+                        .map_err(|_| format!("invalid value: {}", token)),
+                }
+            }
+            c => Err(format!("unsupported value starting with {:?}", c)),
+        }
+    }
+}
+
+/// Parses a single JSON object whose values are all scalars, which is all
+/// netpwn ever writes.
+pub fn parse_object(input: &str) -> Result<Vec<(String, Value)>, String> {
+    let mut parser = Parser {
+        chars: input.chars().peekable(),
+    };
+    let mut fields = Vec::new();
+
+    parser.expect('{')?;
+    parser.skip_whitespace();
+    if parser.chars.peek() == Some(&'}') {
+        parser.chars.next();
+    } else {
+        loop {
+            parser.skip_whitespace();
+            let key = parser.string()?;
+            parser.expect(':')?;
+            fields.push((key, parser.value()?));
+
+            parser.skip_whitespace();
+            match parser.chars.next() {
+                Some(',') => continue,
+                Some('}') => break,
+                c => return Err(format!("expected `,` or `}}`, found {:?}", c)),
+            }
+        }
+    }
+
+    parser.skip_whitespace();
+    if let Some(c) = parser.chars.next() {
+        return Err(format!("trailing characters starting with `{}`", c));
+    }
+
+    Ok(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_scalar_fields() {
+        let fields = parse_object(
+            r#" { "a": null, "b" : true, "c":false, "d": -1.5e3, "e": "x\"\\\/\n\u00e9" } "#,
+        )
+        .unwrap();
+        assert_eq!(
+            fields,
+            [
+                ("a".to_string(), Value::Null),
+                ("b".to_string(), Value::Bool(true)),
+                ("c".to_string(), Value::Bool(false)),
+                ("d".to_string(), Value::Number(-1500.0)),
+                ("e".to_string(), Value::String("x\"\\/\n\u{e9}".to_string())),
+            ]
+        );
+        assert_eq!(parse_object("{}").unwrap(), []);
+        assert_eq!(parse_object(" { } ").unwrap(), []);
+    }
+
+    #[test]
+    fn round_trips_strings() {
+        let value = "quote \" backslash \\ newline \n tab \t nul \0 bell \u{7} ünïcode";
+        let object = format!("{{{}: {}}}", json_string("key"), json_string(value));
+        assert_eq!(
+            parse_object(&object).unwrap(),
+            [("key".to_string(), Value::String(value.to_string()))]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_objects() {
+        for &input in &[
+            "",
+            "[]",
+            "{",
+            "{\"a\"}",
+            "{\"a\": }",
+            "{\"a\": 1,}",
+            "{\"a\": 1 \"b\": 2}",
+            "{a: 1}",
+            "{\"a\": 1} x",
+            "{\"a\": \"unterminated}",
+            "{\"a\": \"\\q\"}",
+            "{\"a\": \"\\u12\"}",
+            "{\"a\": nul}",
+            "{\"a\": 1.2.3}",
+            "{\"a\": [1]}",
+            "{\"a\": {}}",
+        ] {
+            assert!(parse_object(input).is_err(), "{:?}", input);
+        }
+    }
+}
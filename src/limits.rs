@@ -0,0 +1,75 @@
+use std::fmt;
+use std::process::{Child, ExitStatus};
+use std::time::{Duration, Instant};
+
+const WAIT_INTERVAL: Duration = Duration::from_millis(20);
+
+/// How long a single session may take.
+#[derive(Clone, Copy, Default)]
+pub struct Limits {
+    pub timeout: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+    /// Time between SIGTERM and SIGKILL when a limit was exceeded.
+    pub grace: Duration,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Limit {
+    Timeout,
+    IdleTimeout,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Limit::Timeout => write!(f, "timeout"),
+            Limit::IdleTimeout => write!(f, "idle timeout"),
+        }
+    }
+}
+
+impl Limits {
+    pub fn is_set(&self) -> bool {
+        self.timeout.is_some() || self.idle_timeout.is_some()
+    }
+}
+
+/// Waits for the child to exit, giving up once the deadline passed.
+pub fn wait_until(
+    child: &mut Child,
+    deadline: Option<Instant>,
+) -> std::io::Result<Option<ExitStatus>> {
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => return child.wait().map(Some),
+    };
+
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+        std::thread::sleep(WAIT_INTERVAL);
+    }
+}
+
+/// Terminates the child, and its whole process group if it leads one, first
+/// asking nicely and then with SIGKILL once the grace period is over.
+pub fn kill(child: &mut Child, group: bool, grace: Duration) -> std::io::Result<ExitStatus> {
+    let pid = child.id() as libc::pid_t;
+    let pid = if group { -pid } else { pid };
+
+    unsafe { libc::kill(pid, libc::SIGTERM) };
+    if let Some(status) = wait_until(child, Some(Instant::now() + grace))? {
+        if group {
+            // Stragglers of the group should not outlive their leader.
+            unsafe { libc::kill(pid, libc::SIGKILL) };
+        }
+        return Ok(status);
+    }
+
+    unsafe { libc::kill(pid, libc::SIGKILL) };
+    child.wait()
+}
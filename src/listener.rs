@@ -0,0 +1,249 @@
+use crate::json::json_string;
+use std::ffi::CString;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{FromRawFd, OwnedFd};
+use std::os::unix::net::{self, UnixListener};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+const CONNECT_BACKOFF_MIN: Duration = Duration::from_millis(100);
+const CONNECT_BACKOFF_MAX: Duration = Duration::from_secs(5);
+
+/// Socket file that has to be removed when we get killed by a signal.
+static SOCKET_PATH: OnceLock<CString> = OnceLock::new();
+
+pub enum Endpoint {
+    Tcp(SocketAddr),
+    Unix(String),
+    Connect(String),
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, Option<String>),
+    /// Not a listener at all, "accepting" dials out to the given peer.
+    Connect(String),
+}
+
+pub fn parse_connect_addr(addr: &str) -> Result<(), String> {
+    match addr.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(()),
+        _ => Err(format!("invalid address, expected HOST:PORT: {}", addr)),
+    }
+}
+
+pub fn parse_bind_addr(addr: &str) -> Result<IpAddr, String> {
+    if addr == "*" {
+        return Ok(IpAddr::V6(Ipv6Addr::UNSPECIFIED));
+    }
+
+    let addr = addr
+        .strip_prefix('[')
+        .and_then(|x| x.strip_suffix(']'))
+        .unwrap_or(addr);
+    addr.parse()
+        .map_err(|_| format!("invalid bind address: {}", addr))
+}
+
+pub fn set_sockopt(fd: libc::c_int, level: libc::c_int, name: libc::c_int, value: libc::c_int) {
+    unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        );
+    }
+}
+
+fn bind_tcp(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    if addr.ip() != IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+        return TcpListener::bind(addr);
+    }
+
+    // Listening on `::` should accept IPv4 clients as well, regardless of
+    // what the system wide bindv6only default is.
+    let fd = unsafe { libc::socket(libc::AF_INET6, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+
+    set_sockopt(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1);
+    set_sockopt(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, 0);
+
+    let mut sockaddr: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+    sockaddr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+    sockaddr.sin6_port = addr.port().to_be();
+    let ret = unsafe {
+        libc::bind(
+            fd,
+            &sockaddr as *const libc::sockaddr_in6 as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 || unsafe { libc::listen(fd, 128) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(listener)
+}
+
+fn bind_unix(path: &str) -> std::io::Result<UnixListener> {
+    if let Some(name) = path.strip_prefix('@') {
+        let addr = net::SocketAddr::from_abstract_name(name)?;
+        return UnixListener::bind_addr(&addr);
+    }
+
+    // A socket left behind by a previous run would make bind fail.
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+
+    UnixListener::bind(path)
+}
+
+/// Whether a failed connection attempt is worth retrying, because the peer
+/// might just not be up yet.
+fn is_transient(error: &std::io::Error) -> bool {
+    matches!(
+        error.raw_os_error(),
+        Some(
+            libc::ECONNREFUSED
+                | libc::ECONNRESET
+                | libc::ECONNABORTED
+                | libc::ETIMEDOUT
+                | libc::ENETUNREACH
+                | libc::EHOSTUNREACH
+                | libc::EAGAIN
+                | libc::EINTR
+        )
+    )
+}
+
+fn connect(addr: &str) -> std::io::Result<TcpStream> {
+    let failed = |e: std::io::Error| std::io::Error::new(e.kind(), format!("{}: {}", addr, e));
+    // A host that does not resolve is not going to come up by waiting.
+    let addrs: Vec<SocketAddr> = addr.to_socket_addrs().map_err(failed)?.collect();
+    let mut backoff = CONNECT_BACKOFF_MIN;
+    loop {
+        match TcpStream::connect(&addrs[..]) {
+            Ok(stream) => return Ok(stream),
+            Err(e) if is_transient(&e) => {
+                eprintln!("[{}] {}, retrying in {:?}", addr, e, backoff);
+                std::thread::sleep(backoff);
+                backoff = std::cmp::min(backoff * 2, CONNECT_BACKOFF_MAX);
+            }
+            Err(e) => return Err(failed(e)),
+        }
+    }
+}
+
+extern "C" fn remove_socket(signal: libc::c_int) {
+    if let Some(path) = SOCKET_PATH.get() {
+        unsafe {
+            libc::unlink(path.as_ptr());
+        }
+    }
+    unsafe {
+        libc::_exit(128 + signal);
+    }
+}
+
+impl Listener {
+    pub fn bind(endpoint: &Endpoint) -> std::io::Result<Listener> {
+        match endpoint {
+            Endpoint::Tcp(addr) => Ok(Listener::Tcp(bind_tcp(*addr)?)),
+            Endpoint::Unix(path) => {
+                let listener = bind_unix(path)?;
+                if path.starts_with('@') {
+                    return Ok(Listener::Unix(listener, None));
+                }
+
+                let _ = SOCKET_PATH.set(CString::new(path.as_str())?);
+                for signal in &[libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+                    unsafe {
+                        libc::signal(
+                            *signal,
+                            remove_socket as extern "C" fn(libc::c_int) as libc::sighandler_t,
+                        );
+                    }
+                }
+
+                Ok(Listener::Unix(listener, Some(path.clone())))
+            }
+            Endpoint::Connect(addr) => Ok(Listener::Connect(addr.clone())),
+        }
+    }
+
+    /// Describes where clients can reach us once the socket is bound, either
+    /// as a plain address or as a single line JSON object.
+    pub fn ready_message(&self, json: bool) -> std::io::Result<Option<String>> {
+        let message = match self {
+            Listener::Tcp(listener) => {
+                let addr = listener.local_addr()?;
+                if json {
+                    format!(
+                        "{{\"type\":\"tcp\",\"address\":\"{}\",\"port\":{}}}",
+                        addr.ip(),
+                        addr.port()
+                    )
+                } else {
+                    addr.to_string()
+                }
+            }
+            Listener::Unix(listener, _) => {
+                let addr = listener.local_addr()?;
+                let path = match (addr.as_abstract_name(), addr.as_pathname()) {
+                    (Some(name), _) => format!("@{}", String::from_utf8_lossy(name)),
+                    (_, Some(path)) => path.display().to_string(),
+                    _ => String::new(),
+                };
+                if json {
+                    format!("{{\"type\":\"unix\",\"path\":{}}}", json_string(&path))
+                } else {
+                    format!("unix:{}", path)
+                }
+            }
+            Listener::Connect(_) => return Ok(None),
+        };
+
+        Ok(Some(message))
+    }
+
+    /// Accepts the next client and returns its fd together with a printable
+    /// description of the peer.
+    pub fn accept(&self) -> std::io::Result<(OwnedFd, String)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (client, addr) = listener.accept()?;
+                Ok((client.into(), addr.to_string()))
+            }
+            Listener::Unix(listener, path) => {
+                let (client, _) = listener.accept()?;
+                let peer = match path {
+                    Some(path) => format!("unix:{}", path),
+                    None => "unix:@".to_string(),
+                };
+                Ok((client.into(), peer))
+            }
+            Listener::Connect(addr) => {
+                let client = connect(addr)?;
+                Ok((client.into(), addr.clone()))
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, Some(path)) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
@@ -2,130 +2,1376 @@ extern crate clap;
 extern crate libc;
 extern crate which;
 
-use clap::{App, Arg};
+mod elf;
+mod environment;
+mod fds;
+mod inject;
+mod json;
+mod limits;
+mod listener;
+mod payload;
+mod privileges;
+mod pty;
+mod record;
+mod relay;
+mod replay;
+mod rlimit;
+mod sandbox;
+mod seccomp;
+mod shaping;
+mod sys;
+mod syscalls;
+mod templates;
+
+use clap::{value_t, values_t, App, AppSettings, Arg, ArgMatches, SubCommand};
+use elf::TargetInfo;
+use environment::Environment;
+use inject::Inferior;
+use limits::Limits;
+use listener::{parse_bind_addr, parse_connect_addr, Endpoint, Listener};
+use privileges::Credentials;
+use pty::{Pty, PtyOptions};
+use record::{Process, RecordFormat, Recorder};
+use relay::{RelayMode, TargetIo};
+use rlimit::Rlimit;
+use sandbox::{Bind, Sandbox};
+use seccomp::{Action, Policy};
+use shaping::{NetworkConditions, Shaping, ShapingOption};
+use std::ffi::{OsStr, OsString};
 use std::io::prelude::*;
-use std::net::TcpListener;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream};
 use std::os::unix::io::AsRawFd;
 use std::os::unix::io::FromRawFd;
+use std::os::unix::io::OwnedFd;
+use std::os::unix::io::RawFd;
 use std::os::unix::process::CommandExt;
-use std::process::{Command, Stdio};
-
-const AMD64_TEMPLATE: &str = "
-void _gdb_expr(void) {
-	__asm__ (
-		\"movq $33, %%rax\\n\"
-		\"movl %0, %%edi\\n\"
-		\"movq $0, %%rsi\\n\"
-		\"syscall\\n\"
-		\"movq $33, %%rax\\n\"
-		\"movl %0, %%edi\\n\"
-		\"movq $1, %%rsi\\n\"
-		\"syscall\\n\"
-		\"movq $33, %%rax\\n\"
-		\"movl %0, %%edi\\n\"
-		\"movq $2, %%rsi\\n\"
-		\"syscall\\n\"
-		:: \"b\"(fd) : \"%rax\", \"%rdi\", \"%rsi\", \"%rcx\"
-	);
-}
-";
-
-const X86_TEMPLATE: &str = "
-void _gdb_expr(void) {
-	__asm__ (
-		\"movl $63, %%eax\\n\"
-		\"movl %0, %%ebx\\n\"
-		\"movl $0, %%ecx\\n\"
-		\"syscall\\n\"
-		\"movl $63, %%eax\\n\"
-		\"movl %0, %%ebx\\n\"
-		\"movl $1, %%ecx\\n\"
-		\"syscall\\n\"
-		\"movl $63, %%eax\\n\"
-		\"movl %0, %%ebx\\n\"
-		\"movl $2, %%ecx\\n\"
-		\"syscall\\n\"
-		:: \"b\"(fd) : \"%eax\", \"%ebx\", \"%ecx\"
-	);
-}
-";
-
-fn get_template(path: &str) -> &str {
-    let mut file = std::fs::File::open(path).unwrap();
-    let mut buf: [u8; 5] = [0; 5];
-
-    file.read(&mut buf).unwrap();
-
-    if &buf[0..4] == b"\x7fELF" {
-        let class = buf[4];
-        if class == 1 {
-            return X86_TEMPLATE;
-        }
-        if class == 2 {
-            return AMD64_TEMPLATE;
-        }
-    }
-
-    panic!("Unknown executable file type");
-}
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::time::{Duration, Instant};
 
-fn run(
-    program: &str,
-    port: &str,
-    env_vars: Vec<(&str, &str)>,
+/// Everything needed to start the target for a new client.
+struct Target<'a> {
+    program: &'a str,
+    argv0: Option<OsString>,
+    env: Environment,
+    chdir: Option<PathBuf>,
+    umask: Option<libc::mode_t>,
+    keep_fds: Vec<RawFd>,
     gdb: bool,
-    gdb_args: Option<&str>,
-) -> std::io::Result<()> {
-    let listener = TcpListener::bind(format!("127.0.0.1:{}", port))?;
-    let (client, _) = listener.accept().unwrap();
+    gdb_compile: bool,
+    gdbserver: Option<u16>,
+    /// Where netpwn listens, debugger stubs listen there as well.
+    bind: IpAddr,
+    args: Vec<OsString>,
+    gdb_args: Vec<&'a str>,
+    rlimits: Vec<Rlimit>,
+    sandbox: Option<Sandbox>,
+    credentials: Option<Credentials>,
+    pty: Option<PtyOptions>,
+    relay: Option<RelayMode>,
+    record: Option<(&'a Path, RecordFormat)>,
+    conditions: Option<NetworkConditions>,
+    limits: Limits,
+}
 
-    let mut cmd = if gdb {
-        let syscall_template = get_template(program);
+impl Target<'_> {
+    /// How the target itself is set up, keeping `fds` open on top of the
+    /// descriptors asked for.
+    fn setup(&self, fds: &[RawFd]) -> Setup<'_> {
+        let mut keep_fds = self.keep_fds.clone();
+        keep_fds.extend(fds);
+        keep_fds.sort_unstable();
+        keep_fds.dedup();
 
-        // Unset CLOEXEC
-        unsafe {
-            libc::fcntl(client.as_raw_fd(), libc::F_SETFD, 0);
+        Setup {
+            sandbox: self.sandbox.clone(),
+            credentials: self.credentials.as_ref(),
+            rlimits: &self.rlimits,
+            env: Some(&self.env),
+            argv0: self.argv0.as_deref(),
+            chdir: self.chdir.as_deref(),
+            umask: self.umask,
+            keep_fds,
+        }
+    }
+
+    /// Whether netpwn has to stay around to pass data between the client and
+    /// the target.
+    fn relayed(&self) -> bool {
+        self.pty.is_some() || self.relay.is_some()
+    }
+
+    /// Whether netpwn has to stay around until the target exited. gdbserver
+    /// does not get rid of a target it attached to when it fails to start.
+    fn supervised(&self) -> bool {
+        self.relayed() || self.limits.is_set() || self.gdbserver.is_some()
+    }
+}
+
+/// Quotes a word for use in a command line that gdb passes to the shell.
+fn shell_quote(word: &str) -> String {
+    format!("'{}'", word.replace('\'', "'\\''"))
+}
+
+/// How a process netpwn starts is set up, either the target or gdb.
+#[derive(Default)]
+struct Setup<'a> {
+    sandbox: Option<Sandbox>,
+    credentials: Option<&'a Credentials>,
+    rlimits: &'a [Rlimit],
+    env: Option<&'a Environment>,
+    argv0: Option<&'a OsStr>,
+    chdir: Option<&'a Path>,
+    umask: Option<libc::mode_t>,
+    /// Descriptors besides stdio that stay open, sorted.
+    keep_fds: Vec<RawFd>,
+}
+
+impl Setup<'_> {
+    /// Arguments that make the `exec-wrapper` subcommand set up the process
+    /// the same way, apart from the sandbox and privileges.
+    fn wrapper_args(&self) -> Vec<String> {
+        let mut args: Vec<String> = self
+            .rlimits
+            .iter()
+            .map(|x| format!("--rlimit={}", x))
+            .collect();
+        args.extend(self.env.iter().flat_map(|x| x.args()));
+        if let Some(argv0) = self.argv0 {
+            args.push(format!("--argv0={}", payload::encode(argv0)));
+        }
+        if let Some(dir) = self.chdir {
+            args.push(format!("--chdir={}", payload::encode(dir.as_os_str())));
+        }
+        if let Some(umask) = self.umask {
+            args.push(format!("--umask={:04o}", umask));
+        }
+        for fd in &self.keep_fds {
+            args.push(format!("--keep-fd={}", fd));
+        }
+        args
+    }
+
+    /// Whether there is anything the exec wrapper would have to do.
+    fn needs_wrapper(&self) -> bool {
+        self.sandbox.is_some()
+            || !self.rlimits.is_empty()
+            || self.env.is_some_and(|x| !x.is_inherited())
+            || self.argv0.is_some()
+            || self.chdir.is_some()
+            || self.umask.is_some()
+    }
+}
+
+/// Builds a gdb `exec-wrapper` that runs the inferior through netpwn again so
+/// that setup meant for the target does not apply to gdb itself.
+fn exec_wrapper(setup: &Setup) -> std::io::Result<Option<String>> {
+    if !setup.needs_wrapper() {
+        return Ok(None);
+    }
+
+    let exe = std::env::current_exe()?;
+    let mut wrapper = vec![
+        shell_quote(&exe.to_string_lossy()),
+        "exec-wrapper".to_string(),
+    ];
+    // gdb reads the wrapper as a single line.
+    let args = setup.wrapper_args();
+    let sandbox_args = setup.sandbox.iter().flat_map(Sandbox::args);
+    for arg in args.into_iter().chain(sandbox_args) {
+        wrapper.push(shell_quote(&arg));
+    }
+    wrapper.push("--".to_string());
+
+    Ok(Some(wrapper.join(" ")))
+}
+
+/// Creates a command running `program`, through the exec wrapper when it has
+/// to be sandboxed or filtered. Resource limits are applied right before `program` starts,
+/// privileges are dropped by the wrapper before it sets up the sandbox as
+/// netpwn itself might not be accessible to the target's user. The
+/// environment is only handed over to `program`, never to the wrapper.
+fn command(program: &Path, setup: &Setup) -> std::io::Result<Command> {
+    let mut cmd = match &setup.sandbox {
+        Some(sandbox) => {
+            let mut cmd = Command::new(std::env::current_exe()?);
+            cmd.arg("exec-wrapper")
+                .args(sandbox.args())
+                .args(setup.wrapper_args());
+            cmd.args(setup.credentials.into_iter().flat_map(Credentials::args));
+            cmd.arg("--").arg(program);
+            cmd
+        }
+        None => {
+            let mut cmd = Command::new(program);
+            if let Some(argv0) = setup.argv0 {
+                cmd.arg0(argv0);
+            }
+            if let Some(env) = setup.env {
+                env.attach(&mut cmd)?;
+            }
+            if let Some(dir) = setup.chdir {
+                cmd.current_dir(dir);
+            }
+            if let Some(umask) = setup.umask {
+                unsafe {
+                    cmd.pre_exec(move || {
+                        libc::umask(umask);
+                        Ok(())
+                    });
+                }
+            }
+            if !setup.rlimits.is_empty() {
+                let rlimits = setup.rlimits.to_vec();
+                unsafe {
+                    cmd.pre_exec(move || rlimit::apply(&rlimits));
+                }
+            }
+            if let Some(credentials) = setup.credentials.cloned() {
+                unsafe {
+                    cmd.pre_exec(move || credentials.apply());
+                }
+            }
+            cmd
         }
+    };
+
+    // The wrapper gets the same descriptors, it needs them to pass them on.
+    let keep_fds = setup.keep_fds.clone();
+    unsafe {
+        cmd.pre_exec(move || fds::keep_only(&keep_fds));
+    }
+
+    Ok(cmd)
+}
+
+/// Finds the program on the host and reads its header. Failing here beats
+/// the debugger failing to start the program for no obvious reason.
+fn inspect(target: &Target) -> std::io::Result<(PathBuf, TargetInfo)> {
+    let sandbox = target.sandbox.as_ref();
+    // Relative to the directory the target runs in, as without a debugger.
+    let program = match &target.chdir {
+        Some(dir) if target.program.contains('/') => dir.join(target.program),
+        _ => PathBuf::from(target.program),
+    };
+    let program = match sandbox {
+        Some(sandbox) => sandbox.host_path(&program),
+        None => program,
+    };
+
+    let info = TargetInfo::read(&program)?;
+    if let Some(interpreter) = &info.interpreter {
+        let path = match sandbox {
+            Some(sandbox) => sandbox.host_path(interpreter),
+            None => interpreter.clone(),
+        };
+        if !path.exists() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!(
+                    "{}: the dynamic loader {} for {} executables is missing",
+                    program.display(),
+                    interpreter.display(),
+                    info
+                ),
+            ));
+        }
+    }
+    Ok((program, info))
+}
+
+/// qemu with its gdb stub listening on `port`, set up to run the target, for
+/// executables the host cannot run natively. qemu starts them stopped and
+/// with the stdio it got itself, no gdbserver needed.
+fn emulator(target: &Target, port: u16) -> std::io::Result<Option<Command>> {
+    let (program, info) = inspect(target)?;
+    if info.is_native() {
+        return Ok(None);
+    }
 
-        let gdb_path = which::which("gdb").expect("gdb is not installed");
-        let mut cmd = Command::new(gdb_path);
+    let name = info.qemu_name().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "{}: qemu cannot run {} executables",
+                program.display(),
+                info
+            ),
+        )
+    })?;
+    // A root directory has none of the host's libraries, only a static qemu
+    // runs in there.
+    let rootfs = target.sandbox.as_ref().is_some_and(|x| x.rootfs.is_some());
+    let is_static = |path: &PathBuf| {
+        TargetInfo::read(path)
+            .map(|x| x.interpreter.is_none())
+            .unwrap_or(false)
+    };
+    let qemu = [format!("qemu-{}", name), format!("qemu-{}-static", name)]
+        .iter()
+        .filter_map(|x| which::which(x).ok())
+        .find(|x| !rootfs || is_static(x))
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!(
+                    "{}: {} is needed to debug {} executables",
+                    program.display(),
+                    if rootfs {
+                        format!("a static qemu-{}", name)
+                    } else {
+                        format!("qemu-{}", name)
+                    },
+                    info
+                ),
+            )
+        })?;
 
-        for env_var in env_vars {
+    if !target.bind.is_unspecified() {
+        eprintln!(
+            "qemu cannot listen on {} only, its gdb stub on port {} can be reached on every address",
+            target.bind, port
+        );
+    }
+
+    let mut setup = target.setup(&[]);
+    // qemu passes it on by itself.
+    setup.argv0 = None;
+    if let Some(sandbox) = &mut setup.sandbox {
+        sandbox.bind_program(&qemu);
+    }
+    let mut cmd = command(&qemu, &setup)?;
+    cmd.arg("-g").arg(port.to_string());
+    if let Some(argv0) = &target.argv0 {
+        cmd.arg("-0").arg(argv0);
+    }
+    cmd.arg(target.program);
+    Ok(Some(cmd))
+}
+
+/// A port on the loopback interface nothing listens on right now, for a
+/// debugger stub only we connect to.
+fn free_port() -> std::io::Result<u16> {
+    Ok(TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?
+        .local_addr()?
+        .port())
+}
+
+/// What to run for a client, along with the target if it had to be started
+/// up front for a debugger to attach to.
+struct Launch {
+    cmd: Command,
+    inferior: Option<Inferior>,
+}
+
+fn build_command(
+    client: Option<&OwnedFd>,
+    pty: Option<&Pty>,
+    target: &Target,
+) -> std::io::Result<Launch> {
+    let program = target.program;
+    let cmd = if target.gdb {
+        // The sandbox takes gdb along, the inferior only gets its limits and
+        // root directory. gdb itself reads the program from the host and
+        // passes the client on to the inferior.
+        let sandbox = target.sandbox.as_ref();
+        let client_fds: Vec<RawFd> = client.iter().map(|x| x.as_raw_fd()).collect();
+        let mut inferior = target.setup(&client_fds);
+        inferior.sandbox = sandbox.and_then(Sandbox::for_inferior);
+        inferior.credentials = None;
+        let (program, info) = inspect(target)?;
+
+        // Foreign executables need a gdb that knows about their architecture.
+        let gdb_path = if info.is_native() {
+            which::which("gdb")
+        } else {
+            which::which("gdb-multiarch").or_else(|_| which::which("gdb"))
+        };
+        let gdb_path = gdb_path.expect("gdb is not installed");
+
+        // Native executables are started by us and only handed over to gdb
+        // once their stdio is redirected, which takes neither a compiler nor
+        // a `main` to stop at. Foreign ones run under qemu, which gives them
+        // its own stdio and waits for gdb on its stub. gdb then connects from
+        // outside the sandbox.
+        let attach =
+            client.filter(|_| pty.is_none() && !target.gdb_compile && inject::supports(&info));
+        let stub = if info.is_native() {
+            None
+        } else {
+            let port = free_port()?;
+            emulator(target, port)?.map(|emulator| (port, emulator))
+        };
+        let outside = attach.is_some() || stub.is_some();
+        let mut cmd = command(
+            &gdb_path,
+            &Setup {
+                sandbox: sandbox.filter(|_| !outside).and_then(Sandbox::for_gdb),
+                credentials: target.credentials.as_ref(),
+                keep_fds: if outside {
+                    Vec::new()
+                } else {
+                    inferior.keep_fds.clone()
+                },
+                ..Default::default()
+            },
+        )?;
+
+        // Given first so that breakpoints and such are in place before the
+        // commands starting the inferior run.
+        cmd.args(&target.gdb_args);
+
+        if let Some(rootfs) = sandbox.and_then(|x| x.rootfs.as_ref()) {
             cmd.arg("-ex")
-                .arg(format!("set env {}={}", env_var.0, env_var.1));
+                .arg(format!("set sysroot {}", rootfs.display()));
         }
 
-        cmd.arg("-ex")
-            .arg("start")
-            .arg("-ex")
-            .arg(format!(
+        if let Some((port, mut emulator)) = stub {
+            emulator.args(&target.args);
+            match (pty, client) {
+                (Some(pty), _) => pty.attach(&mut emulator)?,
+                (None, Some(client)) => {
+                    emulator
+                        .stdin(client.try_clone()?)
+                        .stdout(client.try_clone()?)
+                        .stderr(client.try_clone()?);
+                }
+                (None, None) => {}
+            }
+            let child = emulator.spawn()?;
+            cmd.arg("-ex")
+                .arg(format!(
+                    "target remote {}",
+                    SocketAddr::from((Ipv4Addr::LOCALHOST, port))
+                ))
+                .arg(program);
+            return Ok(Launch {
+                cmd,
+                inferior: Some(Inferior {
+                    pid: child.id() as libc::pid_t,
+                    child,
+                }),
+            });
+        }
+
+        if let Some(client) = attach {
+            let setup = target.setup(&client_fds);
+            let mut inferior = command(Path::new(target.program), &setup)?;
+            inferior.args(&target.args);
+            let inferior = inject::spawn(
+                &mut inferior,
+                Some(client.as_raw_fd()),
+                setup.sandbox.is_some(),
+            )?;
+            cmd.arg(format!("--pid={}", inferior.pid)).arg(program);
+            return Ok(Launch {
+                cmd,
+                inferior: Some(inferior),
+            });
+        }
+
+        if let Some(wrapper) = exec_wrapper(&inferior)? {
+            cmd.arg("-ex").arg(format!("set exec-wrapper {}", wrapper));
+        }
+
+        if let Some(pty) = pty {
+            // gdb sets up the terminal of the inferior by itself, no need to
+            // redirect anything after the fact.
+            cmd.arg("-ex")
+                .arg(format!("tty {}", pty.path))
+                .arg("-ex")
+                .arg("start");
+        } else if let Some(client) = client {
+            let syscall_template = templates::get(&info).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{}: {}", program.display(), e),
+                )
+            })?;
+            cmd.arg("-ex").arg("start").arg("-ex").arg(format!(
                 "compile code -raw -- {}",
                 format!("int fd = {};", client.as_raw_fd())
                     + syscall_template.replace("\n", "").as_str()
-            ))
-            .arg(program);
-
-        if let Some(gdb_args) = gdb_args {
-            cmd.arg("--").arg(gdb_args);
+            ));
         }
-
+        cmd.arg("--args").arg(program).args(&target.args);
         cmd
     } else {
-        let mut cmd = Command::new(program);
-        unsafe {
-            cmd.stdin(Stdio::from_raw_fd(client.as_raw_fd()))
-                .stdout(Stdio::from_raw_fd(client.as_raw_fd()))
-                .stderr(Stdio::from_raw_fd(client.as_raw_fd()));
+        let setup = target.setup(&[]);
+        let emulator = match target.gdbserver {
+            Some(port) => emulator(target, port)?,
+            None => None,
+        };
+        let emulated = emulator.is_some();
+        let mut cmd = match emulator {
+            Some(cmd) => cmd,
+            None => command(Path::new(program), &setup)?,
+        };
+        cmd.args(&target.args);
+        if let Some(pty) = pty {
+            pty.attach(&mut cmd)?;
+        } else {
+            if let Some(client) = client {
+                cmd.stdin(client.try_clone()?)
+                    .stdout(client.try_clone()?)
+                    .stderr(client.try_clone()?);
+            }
+            if target.limits.is_set() {
+                // Lets us get rid of everything the target started once it
+                // exceeded its limits. A pty already gets its own session.
+                cmd.process_group(0);
+            }
         }
-        cmd.envs(env_vars);
 
+        if let Some(port) = target.gdbserver.filter(|_| !emulated) {
+            let gdbserver_path = which::which("gdbserver").expect("gdbserver is not installed");
+            let inferior = inject::spawn(&mut cmd, None, setup.sandbox.is_some())?;
+            let mut cmd = command(
+                &gdbserver_path,
+                &Setup {
+                    credentials: target.credentials.as_ref(),
+                    ..Default::default()
+                },
+            )?;
+            // An address left empty listens on every one.
+            let address = if target.bind.is_unspecified() {
+                format!(":{}", port)
+            } else {
+                SocketAddr::new(target.bind, port).to_string()
+            };
+            cmd.arg("--attach")
+                .arg(address)
+                .arg(inferior.pid.to_string());
+            if target.limits.is_set() {
+                cmd.process_group(0);
+            }
+            return Ok(Launch {
+                cmd,
+                inferior: Some(inferior),
+            });
+        }
         cmd
     };
-    cmd.exec();
+
+    Ok(Launch {
+        cmd,
+        inferior: None,
+    })
+}
+
+/// A target whose stdio is connected to us instead of a client.
+struct Relayed {
+    child: Child,
+    inferior: Option<Inferior>,
+    io: TargetIo,
+    /// gdb opens the terminal by path only after it started, keeping our copy
+    /// of the slave around prevents the master from hanging up early.
+    _slave: Option<OwnedFd>,
+}
+
+fn spawn_relayed(target: &Target) -> std::io::Result<Relayed> {
+    let pty = match &target.pty {
+        Some(options) => Some(Pty::open(options)?),
+        None => None,
+    };
+    if let (Some(pty), Some(uid)) = (&pty, target.credentials.as_ref().and_then(|x| x.uid)) {
+        pty.set_owner(uid)?;
+    }
+
+    // gdb can only redirect the target to a single fd, so it always gets a
+    // socket pair when relaying. So does a target started for gdbserver, it
+    // is already running by the time pipes could be set up.
+    let (theirs, io) = match (&pty, target.relay) {
+        (None, Some(mode))
+            if mode == RelayMode::SocketPair || target.gdb || target.gdbserver.is_some() =>
+        {
+            let (theirs, io) = TargetIo::socketpair()?;
+            (Some(theirs), Some(io))
+        }
+        _ => (None, None),
+    };
+
+    let Launch { mut cmd, inferior } = build_command(theirs.as_ref(), pty.as_ref(), target)?;
+    let io = match io {
+        Some(io) => Some(io),
+        None if pty.is_none() => Some(TargetIo::pipes(&mut cmd)?),
+        None => None,
+    };
+    let child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            if let Some(inferior) = inferior {
+                inferior.kill();
+            }
+            return Err(e);
+        }
+    };
+
+    Ok(match pty {
+        Some(pty) => Relayed {
+            child,
+            inferior,
+            io: TargetIo::pty(pty.master)?,
+            _slave: if target.gdb { Some(pty.slave) } else { None },
+        },
+        None => Relayed {
+            child,
+            inferior,
+            io: io.unwrap(),
+            _slave: None,
+        },
+    })
+}
+
+/// Tells how to attach to the target of `client`, on the address the client
+/// reached us on, which gdbserver listens on as well.
+fn announce_gdbserver(client: &OwnedFd, peer: &str, port: u16) {
+    let host = client
+        .try_clone()
+        .map(TcpStream::from)
+        .and_then(|x| x.local_addr());
+    let address = match host {
+        Ok(host) => SocketAddr::new(host.ip().to_canonical(), port).to_string(),
+        Err(_) => format!("localhost:{}", port),
+    };
+    eprintln!("[{}] debug with: target remote {}", peer, address);
+}
+
+/// Ignores the keys typed into the terminal while gdb has it, they are
+/// meant for gdb which shares our process group.
+struct TerminalSignals([libc::sighandler_t; 2]);
+
+impl TerminalSignals {
+    fn ignore() -> TerminalSignals {
+        unsafe {
+            TerminalSignals([
+                libc::signal(libc::SIGINT, libc::SIG_IGN),
+                libc::signal(libc::SIGQUIT, libc::SIG_IGN),
+            ])
+        }
+    }
+}
+
+impl Drop for TerminalSignals {
+    fn drop(&mut self) {
+        unsafe {
+            libc::signal(libc::SIGINT, self.0[0]);
+            libc::signal(libc::SIGQUIT, self.0[1]);
+        }
+    }
+}
+
+/// Starts the target for a single client and, if the target does not talk to
+/// the client directly, relays between the two until the session is over.
+fn session(client: OwnedFd, peer: &str, target: &Target) -> std::io::Result<()> {
+    let spawned = if target.relayed() {
+        spawn_relayed(target).map(|relayed| {
+            (
+                relayed.child,
+                relayed.inferior,
+                Some((relayed.io, relayed._slave)),
+            )
+        })
+    } else {
+        build_command(Some(&client), None, target).and_then(|mut launch| match launch.cmd.spawn() {
+            Ok(child) => Ok((child, launch.inferior, None)),
+            Err(e) => {
+                if let Some(inferior) = launch.inferior {
+                    inferior.kill();
+                }
+                Err(e)
+            }
+        })
+    };
+    let (mut child, inferior, relayed) = match spawned {
+        Ok(spawned) => spawned,
+        Err(e) => {
+            eprintln!("[{}] failed to spawn {}: {}", peer, target.program, e);
+            return Ok(());
+        }
+    };
+    eprintln!("[{}] spawned pid {}", peer, child.id());
+    let _signals = if target.gdb {
+        Some(TerminalSignals::ignore())
+    } else {
+        None
+    };
+    if let Some(port) = target.gdbserver {
+        announce_gdbserver(&client, peer, port);
+    }
+
+    let mut recorder = match target.record {
+        Some((dir, format)) => Some(Recorder::create(
+            dir,
+            format,
+            peer,
+            target.program,
+            child.id(),
+        )?),
+        None => None,
+    };
+
+    let deadline = target.limits.timeout.map(|x| Instant::now() + x);
+    let mut exceeded = None;
+    if let Some((io, _slave)) = relayed {
+        if target.conditions.is_some() {
+            // Fragments should reach the client one by one instead of being
+            // coalesced by Nagle's algorithm.
+            listener::set_sockopt(client.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_NODELAY, 1);
+        }
+        exceeded = relay::relay(
+            client,
+            io,
+            recorder.as_mut(),
+            target.conditions.as_ref(),
+            &target.limits,
+        )?;
+    } else {
+        drop(client);
+    }
+
+    let status = match exceeded {
+        Some(_) => None,
+        None => limits::wait_until(&mut child, deadline)?,
+    };
+    let status = match status {
+        Some(status) => status,
+        None => {
+            let limit = exceeded.unwrap_or(limits::Limit::Timeout);
+            eprintln!("[{}] {} exceeded, killing pid {}", peer, limit, child.id());
+            // gdb has to stay in our process group to use the terminal.
+            limits::kill(&mut child, !target.gdb, target.limits.grace)?
+        }
+    };
+    eprintln!("[{}] pid {} exited with {}", peer, child.id(), status);
+    // Started by us rather than the debugger, which leaves it behind.
+    let exit = match inferior {
+        Some(inferior) => {
+            let pid = inferior.pid;
+            let status = inferior.finish()?;
+            eprintln!("[{}] target pid {} exited with {}", peer, pid, status);
+            (Process::Target, status)
+        }
+        None if target.gdb => (Process::Gdb, status),
+        None => (Process::Target, status),
+    };
+    if let Some(recorder) = recorder.as_mut() {
+        recorder.exit(exit.0, exit.1)?;
+    }
+
+    Ok(())
+}
+
+/// Where to announce the bound address once clients can connect.
+struct Readiness<'a> {
+    json: bool,
+    file: Option<&'a str>,
+    fd: Option<i32>,
+}
+
+fn notify_ready(listener: &Listener, ready: &Readiness) -> std::io::Result<()> {
+    let message = match listener.ready_message(false)? {
+        Some(message) => message,
+        None => return Ok(()),
+    };
+    eprintln!("listening on {}", message);
+
+    let message = if ready.json {
+        let message = listener.ready_message(true)?.unwrap();
+        let mut stdout = std::io::stdout();
+        writeln!(stdout, "{}", message)?;
+        stdout.flush()?;
+        message
+    } else {
+        message
+    };
+
+    if let Some(path) = ready.file {
+        // Write to a temporary file first so that pollers never observe a
+        // partially written notification.
+        let tmp = format!("{}.tmp", path);
+        std::fs::write(&tmp, format!("{}\n", message))?;
+        std::fs::rename(&tmp, path)?;
+    }
+
+    if let Some(fd) = ready.fd {
+        if unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut file = unsafe { std::fs::File::from_raw_fd(fd) };
+        writeln!(file, "{}", message)?;
+    }
 
     Ok(())
 }
 
+fn run(
+    target: &Target,
+    endpoint: &Endpoint,
+    forever: bool,
+    ready: &Readiness,
+) -> std::io::Result<()> {
+    let listener = Listener::bind(endpoint)?;
+    notify_ready(&listener, ready)?;
+
+    if !forever {
+        let (client, peer) = listener.accept()?;
+        drop(listener);
+        if target.supervised() {
+            return session(client, &peer, target);
+        }
+        // An inferior started up front becomes a child of gdb.
+        let mut launch = build_command(Some(&client), None, target)?;
+        return Err(launch.cmd.exec());
+    }
+
+    // When dialing out there is nobody queueing up connections, so instead
+    // of reconnecting right away the current session has to finish first.
+    // gdb needs the terminal for itself, gdbserver and qemu the port.
+    let sequential =
+        matches!(listener, Listener::Connect(_)) || target.gdb || target.gdbserver.is_some();
+
+    std::thread::scope(|scope| loop {
+        let (client, peer) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                eprintln!("failed to accept a connection: {}", e);
+                // Running out of descriptors would make us spin otherwise.
+                std::thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+        };
+        if sequential {
+            session(client, &peer, target)?;
+            continue;
+        }
+
+        // Each session waits for its own target, which keeps finished ones
+        // from lingering as zombies.
+        scope.spawn(move || {
+            if let Err(e) = session(client, &peer, target) {
+                eprintln!("[{}] {}", peer, e);
+            }
+        });
+    })
+}
+
+/// Applies all values given for a shaping option to the matching directions.
+fn parse_shaping<T, F, S>(
+    matches: &ArgMatches,
+    name: &str,
+    conditions: &mut NetworkConditions,
+    parse: F,
+    set: S,
+) where
+    F: Fn(&str) -> Option<T> + Copy,
+    S: Fn(&mut Shaping, T),
+    T: Copy,
+{
+    for option in matches.values_of(name).into_iter().flatten() {
+        let ShapingOption {
+            client_to_target,
+            target_to_client,
+            value,
+        } = shaping::parse_option(option, parse).unwrap();
+        if client_to_target {
+            set(&mut conditions.client_to_target, value);
+        }
+        if target_to_client {
+            set(&mut conditions.target_to_client, value);
+        }
+    }
+}
+
+fn parse_conditions(matches: &ArgMatches) -> Option<NetworkConditions> {
+    if !["latency", "fragment", "bandwidth", "dribble"]
+        .iter()
+        .any(|x| matches.is_present(x))
+    {
+        return None;
+    }
+
+    let mut conditions = NetworkConditions::default();
+    parse_shaping(
+        matches,
+        "latency",
+        &mut conditions,
+        shaping::parse_millis,
+        |x, v| x.latency = v,
+    );
+    parse_shaping(
+        matches,
+        "fragment",
+        &mut conditions,
+        shaping::parse_fragment,
+        |x, v| x.fragment = Some(v),
+    );
+    parse_shaping(
+        matches,
+        "bandwidth",
+        &mut conditions,
+        shaping::parse_bandwidth,
+        |x, v| x.bandwidth = Some(v),
+    );
+    parse_shaping(
+        matches,
+        "dribble",
+        &mut conditions,
+        shaping::parse_millis,
+        |x, v| x.dribble = Some(v),
+    );
+
+    conditions.seed = match value_t!(matches, "seed", u64) {
+        Ok(seed) => seed,
+        Err(_) => std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|x| x.as_nanos() as u64)
+            .unwrap_or(1),
+    };
+    eprintln!("shaping traffic with seed {}", conditions.seed);
+
+    Some(conditions)
+}
+
+fn validate_secs(value: String) -> Result<(), String> {
+    // Deadlines are computed by adding it to the current time.
+    match value.parse().map(Duration::try_from_secs_f64) {
+        Ok(Ok(secs)) if Instant::now().checked_add(secs).is_some() => Ok(()),
+        _ => Err(format!("invalid number of seconds: {}", value)),
+    }
+}
+
+fn validate_groups(groups: String) -> Result<(), String> {
+    groups
+        .split(',')
+        .filter(|x| !x.is_empty())
+        .try_for_each(|x| privileges::lookup_group(x).map(|_| ()))
+}
+
+fn parse_umask(umask: &str) -> Result<libc::mode_t, String> {
+    match libc::mode_t::from_str_radix(umask, 8) {
+        Ok(umask) if umask <= 0o777 => Ok(umask),
+        _ => Err(format!(
+            "invalid umask, expected an octal mode like 022: {}",
+            umask
+        )),
+    }
+}
+
+/// Arguments describing whom the target runs as, shared with the exec wrapper.
+fn privilege_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("user")
+            .long("user")
+            .value_name("USER")
+            .help("runs the executable as the given user name or uid, dropping all other groups, after binding as whoever started netpwn")
+            .takes_value(true)
+            .validator(|x| privileges::lookup_user(&x).map(|_| ())),
+        Arg::with_name("group")
+            .long("group")
+            .value_name("GROUP")
+            .help("runs the executable with the given group name or gid, defaults to the primary group of --user")
+            .takes_value(true)
+            .validator(|x| privileges::lookup_group(&x).map(|_| ())),
+        Arg::with_name("supplementary_groups")
+            .long("supplementary-groups")
+            .value_name("GROUPS")
+            .help("comma separated list of supplementary groups of the executable")
+            .takes_value(true)
+            .validator(validate_groups),
+    ]
+}
+
+/// Arguments describing the sandbox, shared with the exec wrapper.
+fn sandbox_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("sandbox")
+            .long("sandbox")
+            .help("runs the executable in new user, mount, pid, network, ipc and uts namespaces with a fresh /proc, leaving the client as its only connection, together with gdb when debugging"),
+        Arg::with_name("sandbox_readonly")
+            .long("sandbox-readonly")
+            .help("like --sandbox but also makes the whole filesystem read-only except for a tmpfs on /tmp"),
+        Arg::with_name("rootfs")
+            .long("rootfs")
+            .value_name("DIR")
+            .help("changes the root directory of the executable to DIR, in which PROGRAM is looked up, for example an extracted container image, with the same escapes as -e")
+            .takes_value(true)
+            .validator_os(|x| match payload::decode(x) {
+                Ok(dir) if Path::new(&dir).is_dir() => Ok(()),
+                Ok(_) => Err(format!("not a directory: {}", x.to_string_lossy()).into()),
+                Err(e) => Err(e.into()),
+            }),
+        Arg::with_name("rootfs_bind")
+            .long("rootfs-bind")
+            .value_name("SOURCE[:DEST]")
+            .help("bind-mounts a path of the host into the root directory, replacing the defaults /dev/null, /dev/urandom and /proc, an empty value binds nothing, with the same escapes as -e and `\\x3a` for a colon")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .requires("rootfs")
+            .validator(|x| {
+                if x.is_empty() {
+                    return Ok(());
+                }
+                x.parse::<Bind>().map(|_| ())
+            }),
+        Arg::with_name("seccomp")
+            .long("seccomp")
+            .value_name("POLICY")
+            .help("filters the syscalls of the executable with a policy file made of `allow` and `deny` lines listing syscall names, optionally in `[amd64]` or `[x86]` sections, anything allowed denies all other syscalls, with the same escapes as -e. execve is let through by the address of the path the executable is started with, which the executable could pass again")
+            .takes_value(true)
+            .validator_os(|x| {
+                let path = payload::decode(x)?;
+                Policy::load(Path::new(&path), Action::Kill)
+                    .map(|_| ())
+                    .map_err(|e| e.to_string().into())
+            }),
+        Arg::with_name("seccomp_action")
+            .long("seccomp-action")
+            .value_name("ACTION")
+            .help("sets what happens to denied syscalls, `kill` the process, fail them with `errno[:N]` or just `log` them")
+            .takes_value(true)
+            .default_value("kill")
+            .validator(|x| x.parse::<Action>().map(|_| ())),
+    ]
+}
+
+fn target_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    let mut args = vec![
+        Arg::with_name("env")
+            .long("env")
+            .short("e")
+            .value_name("KEY=VALUE")
+            .help("sets an environment variable of the executable, `\\xNN` stands for an arbitrary byte and `\\\\` for a backslash")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .validator_os(|x| payload::parse_env(x).map(|_| ()).map_err(OsString::from)),
+        Arg::with_name("env_file")
+            .long("env-file")
+            .value_name("PATH")
+            .help("reads NUL separated KEY=VALUE environment variables from a file, like /proc/PID/environ, skipping entries without =")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name("clean_env")
+            .long("clean-env")
+            .help("starts the executable with an empty environment instead of the one of netpwn, variables from -e and --env-file are set in the order given"),
+        Arg::with_name("argv0")
+            .long("argv0")
+            .value_name("NAME")
+            .help("passes NAME as argv[0] instead of the program, with the same escapes as -e")
+            .takes_value(true)
+            .validator_os(|x| payload::decode(x).map(|_| ()).map_err(OsString::from)),
+        Arg::with_name("match_remote")
+            .long("match-remote")
+            .help("keeps the stack layout the same with and without gdb by leaving out the variables gdb adds (LINES and COLUMNS) and passing the program as argv[0] as given"),
+        Arg::with_name("arg_file")
+            .long("arg-file")
+            .value_name("PATH")
+            .help("reads NUL separated arguments from a file and passes them after ARGS, a file without NUL bytes is a single argument")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name("chdir")
+            .long("chdir")
+            .value_name("DIR")
+            .help("runs the executable in DIR, inside the root directory if there is one, a relative program is looked up from there too")
+            .takes_value(true),
+        Arg::with_name("umask")
+            .long("umask")
+            .value_name("MODE")
+            .help("sets the umask of the executable, in octal")
+            .takes_value(true)
+            .validator(|x| parse_umask(&x).map(|_| ())),
+        Arg::with_name("keep_fd")
+            .long("keep-fd")
+            .value_name("FD")
+            .help("passes an open file descriptor of netpwn on to the executable under the same number, all but stdin, stdout and stderr are closed otherwise")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .validator(|x| {
+                x.parse::<RawFd>()
+                    .map(|_| ())
+                    .map_err(|_| format!("invalid file descriptor: {}", x))
+            }),
+        Arg::with_name("rlimit")
+            .long("rlimit")
+            .value_name("NAME=SOFT[:HARD]")
+            .help("sets a resource limit of the executable, NAME is one of as, core, cpu, data, fsize, memlock, nofile, nproc or stack and limits may be `unlimited` or use a K, M or G suffix")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .validator(|x| x.parse::<Rlimit>().map(|_| ())),
+        Arg::with_name("pty")
+            .long("pty")
+            .short("t")
+            .help("gives the executable a pseudo-terminal instead of the raw socket"),
+        Arg::with_name("pty_raw")
+            .long("pty-raw")
+            .help("puts the pseudo-terminal into raw mode")
+            .requires("pty"),
+        Arg::with_name("pty_no_echo")
+            .long("pty-no-echo")
+            .help("disables echoing of input on the pseudo-terminal")
+            .requires("pty"),
+        Arg::with_name("pty_size")
+            .long("pty-size")
+            .value_name("COLSxROWS")
+            .help("sets the initial window size of the pseudo-terminal")
+            .takes_value(true)
+            .requires("pty")
+            .validator(|x| pty::parse_size(&x).map(|_| ())),
+        Arg::with_name("relay")
+            .long("relay")
+            .short("r")
+            .value_name("MODE")
+            .help("passes data between the client and the executable instead of handing over the socket, the executable is connected through a `pipe` or a `socketpair`")
+            .takes_value(true)
+            .possible_values(&["pipe", "socketpair"])
+            .conflicts_with("pty"),
+        Arg::with_name("gdb")
+            .long("gdb")
+            .short("g")
+            .help("defines whether gdb should be setup"),
+        Arg::with_name("gdbserver")
+            .long("gdbserver")
+            .value_name("PORT")
+            .help("starts the executable stopped under gdbserver listening on PORT of the --bind address, or under qemu for foreign architectures, which listens on every address, and prints the `target remote` command to attach with")
+            .takes_value(true)
+            .conflicts_with("gdb")
+            .validator(|x| {
+                x.parse::<u16>()
+                    .map(|_| ())
+                    .map_err(|_| format!("invalid port: {}", x))
+            }),
+        Arg::with_name("gdb_compile")
+            .long("gdb-compile")
+            .help("redirects the stdio of the executable with gdb's `compile code`, which needs a compiler and the executable's `main`, even where netpwn could do so through ptrace. That is only possible for amd64 and i386 executables on amd64 hosts, other native executables always go through gdb and foreign ones get their stdio from qemu")
+            .requires("gdb"),
+        Arg::with_name("gdb_arg")
+            .long("gdb-arg")
+            .value_name("ARG")
+            .help("passes an argument to gdb itself, for example `--gdb-arg=-x --gdb-arg=script.gdb`")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .allow_hyphen_values(true)
+            .requires("gdb"),
+        Arg::with_name("program")
+            .value_name("PROGRAM")
+            .required(true)
+            .help("program to execute"),
+        Arg::with_name("args")
+            .value_name("ARGS")
+            .multiple(true)
+            .last(true)
+            .help("arguments that shall be passed to the program, under gdb as well"),
+    ];
+    args.extend(privilege_args());
+    args.extend(sandbox_args());
+    args
+}
+
+fn parse_sandbox(matches: &ArgMatches) -> Option<Sandbox> {
+    let readonly = matches.is_present("sandbox_readonly");
+    let isolate = matches.is_present("sandbox") || readonly;
+    let rootfs = matches.value_of_os("rootfs").map(|x| {
+        std::fs::canonicalize(payload::decode(x).unwrap())
+            .expect("Failed to resolve the root directory")
+    });
+    let seccomp = matches.value_of_os("seccomp").map(|x| {
+        (
+            std::fs::canonicalize(payload::decode(x).unwrap())
+                .expect("Failed to resolve the seccomp policy"),
+            value_t!(matches, "seccomp_action", Action).unwrap(),
+        )
+    });
+    if !isolate && rootfs.is_none() && seccomp.is_none() {
+        return None;
+    }
+
+    let binds = match matches.values_of("rootfs_bind") {
+        Some(binds) => binds
+            .filter(|x| !x.is_empty())
+            .map(|x| x.parse().unwrap())
+            .collect(),
+        None => sandbox::DEFAULT_BINDS
+            .iter()
+            .map(|x| x.parse().unwrap())
+            .collect(),
+    };
+
+    Some(Sandbox {
+        isolate,
+        readonly,
+        rootfs,
+        binds,
+        seccomp,
+    })
+}
+
+fn parse_credentials(matches: &ArgMatches) -> Option<Credentials> {
+    if !["user", "group", "supplementary_groups"]
+        .iter()
+        .any(|x| matches.is_present(x))
+    {
+        return None;
+    }
+
+    let user = matches
+        .value_of("user")
+        .map(|x| privileges::lookup_user(x).unwrap());
+    let gid = match (matches.value_of("group"), user) {
+        (Some(group), _) => Some(privileges::lookup_group(group).unwrap()),
+        (None, Some((_, Some(gid)))) => Some(gid),
+        (None, Some((uid, None))) => {
+            panic!("User {} has no primary group, pass --group", uid)
+        }
+        (None, None) => None,
+    };
+    let groups = matches
+        .value_of("supplementary_groups")
+        .unwrap_or("")
+        .split(',')
+        .filter(|x| !x.is_empty())
+        .map(|x| privileges::lookup_group(x).unwrap())
+        .collect();
+
+    Some(Credentials {
+        uid: user.map(|x| x.0),
+        gid,
+        groups,
+    })
+}
+
+fn parse_target<'a>(matches: &'a ArgMatches) -> Target<'a> {
+    let gdb = matches.is_present("gdb");
+    let gdb_compile = matches.is_present("gdb_compile");
+    let program = matches.value_of("program").unwrap();
+    let gdb_args = matches.values_of("gdb_arg").into_iter().flatten().collect();
+
+    let read_entries = |name| {
+        let paths = matches.values_of_os(name).into_iter().flatten();
+        paths.flat_map(|path| {
+            let entries = payload::read_entries(Path::new(path))
+                .unwrap_or_else(|e| panic!("Failed to read {}: {}", path.to_string_lossy(), e));
+            entries.into_iter().map(move |x| (path, x))
+        })
+    };
+
+    // Variables given on the command line win over the ones from files.
+    let mut vars: Vec<(OsString, OsString)> = read_entries("env_file")
+        .filter_map(|(path, x)| match payload::split_env(&x) {
+            Ok(var) => Some(var),
+            // execve takes entries without `=`, but there is no setting them.
+            Err(e) => {
+                eprintln!("Skipping entry of {}: {}", path.to_string_lossy(), e);
+                None
+            }
+        })
+        .collect();
+    for var in matches.values_of_os("env").into_iter().flatten() {
+        vars.push(payload::parse_env(var).unwrap());
+    }
+    let match_remote = matches.is_present("match_remote");
+    let env = Environment {
+        clean: matches.is_present("clean_env"),
+        vars,
+        unset: if match_remote {
+            environment::GDB_VARIABLES
+                .iter()
+                .map(OsString::from)
+                .collect()
+        } else {
+            Vec::new()
+        },
+    };
+    // gdb starts the program by its full path, which would end up as argv[0].
+    let argv0 = match matches.value_of_os("argv0") {
+        Some(argv0) => Some(payload::decode(argv0).unwrap()),
+        None if match_remote => Some(OsString::from(program)),
+        None => None,
+    };
+
+    let mut args: Vec<OsString> = matches
+        .values_of_os("args")
+        .into_iter()
+        .flatten()
+        .map(OsString::from)
+        .collect();
+    args.extend(read_entries("arg_file").map(|(_, x)| x));
+
+    let pty = if matches.is_present("pty") {
+        let (cols, rows) =
+            pty::parse_size(matches.value_of("pty_size").unwrap_or("80x24")).unwrap();
+        Some(PtyOptions {
+            raw: matches.is_present("pty_raw"),
+            echo: !matches.is_present("pty_raw") && !matches.is_present("pty_no_echo"),
+            cols,
+            rows,
+        })
+    } else {
+        None
+    };
+
+    let keep_fds = values_t!(matches, "keep_fd", RawFd).unwrap_or_default();
+    for &fd in &keep_fds {
+        if unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+            panic!("File descriptor {} is not open", fd);
+        }
+    }
+
+    let sandbox = parse_sandbox(matches);
+    let gdbserver = matches.value_of("gdbserver").map(|x| x.parse().unwrap());
+    // Redirecting the target's stdio and letting the debugger attach happens
+    // from within the target, after the filter is in place.
+    if let Some((path, action)) = sandbox.as_ref().and_then(|x| x.seccomp.as_ref()) {
+        let needed: &[&str] = match (gdb, gdbserver) {
+            (true, _) if gdb_compile => &["dup2", "close"],
+            (true, _) => &["dup2", "close", "prctl"],
+            (false, Some(_)) => &["prctl"],
+            (false, None) => &[],
+        };
+        let policy = Policy::load(path, *action).unwrap();
+        let blocked: Vec<&str> = needed
+            .iter()
+            .copied()
+            .filter(|x| policy.blocks(x))
+            .collect();
+        if !blocked.is_empty() {
+            panic!(
+                "The seccomp policy has to allow {} for debugging the target",
+                blocked.join(", ")
+            );
+        }
+    }
+
+    Target {
+        program,
+        argv0,
+        env,
+        chdir: matches.value_of_os("chdir").map(PathBuf::from),
+        umask: matches.value_of("umask").map(|x| parse_umask(x).unwrap()),
+        keep_fds,
+        gdb,
+        gdb_compile,
+        gdbserver,
+        bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+        args,
+        gdb_args,
+        rlimits: values_t!(matches, "rlimit", Rlimit).unwrap_or_default(),
+        sandbox,
+        credentials: parse_credentials(matches),
+        pty,
+        relay: value_t!(matches, "relay", RelayMode).ok(),
+        record: None,
+        conditions: None,
+        limits: Limits::default(),
+    }
+}
+
 fn main() {
     let matches = App::new("netpwn")
         .arg(
@@ -133,59 +1379,363 @@ fn main() {
                 .long("port")
                 .short("p")
                 .value_name("PORT")
-                .help("sets the port the server should listen on")
+                .help("sets the port the server should listen on, 0 picks a free one")
+                .takes_value(true)
+                .validator(|x| {
+                    x.parse::<u16>()
+                        .map(|_| ())
+                        .map_err(|_| format!("invalid port: {}", x))
+                }),
+        )
+        .arg(
+            Arg::with_name("bind")
+                .long("bind")
+                .short("b")
+                .value_name("ADDRESS")
+                .help("sets the address the server should listen on, `::` or `*` listens on IPv4 and IPv6")
+                .takes_value(true)
+                .validator(|x| parse_bind_addr(&x).map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("unix")
+                .long("unix")
+                .short("u")
+                .value_name("PATH")
+                .help("listens on a unix domain socket instead, a leading `@` selects the abstract namespace")
+                .takes_value(true)
+                .conflicts_with_all(&["port", "bind"]),
+        )
+        .arg(
+            Arg::with_name("connect")
+                .long("connect")
+                .short("c")
+                .value_name("HOST:PORT")
+                .help("connects to the given address instead of listening, retrying while it refuses connections or is unreachable")
+                .takes_value(true)
+                .validator(|x| parse_connect_addr(&x))
+                .conflicts_with_all(&["port", "bind", "unix", "ready_json", "ready_file", "ready_fd"]),
+        )
+        .arg(
+            Arg::with_name("ready_json")
+                .long("ready-json")
+                .help("prints the bound address as JSON on stdout once the server is listening"),
+        )
+        .arg(
+            Arg::with_name("ready_file")
+                .long("ready-file")
+                .value_name("PATH")
+                .help("writes the bound address to the given file once the server is listening")
                 .takes_value(true),
         )
         .arg(
-            Arg::with_name("env")
-                .long("env")
-                .short("e")
-                .value_name("ENVIRONMENT")
-                .help("sets the environment variables that will be present in the executable")
+            Arg::with_name("ready_fd")
+                .long("ready-fd")
+                .value_name("FD")
+                .help("writes the bound address to the given fd and closes it once the server is listening")
+                .takes_value(true)
+                .validator(|x| {
+                    x.parse::<i32>()
+                        .map(|_| ())
+                        .map_err(|_| format!("invalid fd: {}", x))
+                }),
+        )
+        .arg(
+            Arg::with_name("record")
+                .long("record")
+                .value_name("DIR")
+                .help("records a transcript of every session into the given directory, implies `--relay pipe` unless another relay mode is given")
                 .takes_value(true),
         )
         .arg(
-            Arg::with_name("gdb")
-                .long("gdb")
-                .short("g")
-                .help("defines whether gdb should be setup"),
+            Arg::with_name("record_format")
+                .long("record-format")
+                .value_name("FORMAT")
+                .help("sets the format of recorded transcripts")
+                .takes_value(true)
+                .possible_values(&["json", "hexdump"])
+                .default_value("json"),
+        )
+        .arg(
+            Arg::with_name("latency")
+                .long("latency")
+                .value_name("[in:|out:]MS")
+                .help("delays data by the given number of milliseconds, `in` only applies to data sent by the client and `out` to data sent by the executable")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|x| shaping::parse_option(&x, shaping::parse_millis).map(|_| ())),
         )
         .arg(
-            Arg::with_name("program")
-                .value_name("PROGRAM")
-                .required(true)
-                .help("program to execute"),
+            Arg::with_name("fragment")
+                .long("fragment")
+                .value_name("[in:|out:]MIN[-MAX]")
+                .help("splits data into fragments of random sizes in the given range")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|x| shaping::parse_option(&x, shaping::parse_fragment).map(|_| ())),
         )
         .arg(
-            Arg::with_name("gdb_args")
-                .value_name("GDBARGS")
-                .last(true)
-                .help("arguments that shall be passed to gdb"),
+            Arg::with_name("bandwidth")
+                .long("bandwidth")
+                .value_name("[in:|out:]BYTES")
+                .help("limits the throughput to the given number of bytes per second")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|x| shaping::parse_option(&x, shaping::parse_bandwidth).map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("dribble")
+                .long("dribble")
+                .value_name("[in:|out:]MS")
+                .help("sends data byte by byte waiting the given number of milliseconds in between")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|x| shaping::parse_option(&x, shaping::parse_millis).map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .value_name("SEED")
+                .help("sets the seed used for random fragment sizes to reproduce a previous run")
+                .takes_value(true)
+                .validator(|x| {
+                    x.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| format!("invalid seed: {}", x))
+                }),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .long("timeout")
+                .value_name("SECS")
+                .help("kills the executable and its process group after the given number of seconds")
+                .takes_value(true)
+                .validator(validate_secs),
+        )
+        .arg(
+            Arg::with_name("idle_timeout")
+                .long("idle-timeout")
+                .value_name("SECS")
+                .help("kills the executable and its process group once no data was exchanged for the given number of seconds, implies `--relay pipe` unless another relay mode is given")
+                .takes_value(true)
+                .validator(validate_secs),
+        )
+        .arg(
+            Arg::with_name("kill_grace")
+                .long("kill-grace")
+                .value_name("SECS")
+                .help("sets how long to wait after SIGTERM before sending SIGKILL when a timeout is exceeded")
+                .takes_value(true)
+                .default_value("2")
+                .validator(validate_secs),
+        )
+        .arg(
+            Arg::with_name("forever")
+                .long("forever")
+                .short("f")
+                .help("keeps listening and spawns a new process for every connection, one connection at a time with --gdb or --gdbserver"),
+        )
+        .args(&target_args())
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(
+            SubCommand::with_name("replay")
+                .about("replays the client side of a recorded session against the program and reports the first difference in its output")
+                .arg(
+                    Arg::with_name("transcript")
+                        .value_name("TRANSCRIPT")
+                        .required(true)
+                        .help("transcript recorded in the json format"),
+                )
+                .arg(
+                    Arg::with_name("wait")
+                        .long("wait")
+                        .short("w")
+                        .help("waits for the recorded output before sending each chunk"),
+                )
+                .arg(
+                    Arg::with_name("wait_timeout")
+                        .long("wait-timeout")
+                        .value_name("SECS")
+                        .help("sets how long to wait for output of the program")
+                        .takes_value(true)
+                        .default_value("5")
+                        .validator(validate_secs),
+                )
+                .args(&target_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("exec-wrapper")
+                .about("applies the given setup to itself and executes the command, used to set up processes started by gdb")
+                .setting(AppSettings::Hidden)
+                .arg(
+                    Arg::with_name("rlimit")
+                        .long("rlimit")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(Arg::with_name("clean_env").long("clean-env"))
+                .arg(
+                    Arg::with_name("env")
+                        .long("env")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("unset")
+                        .long("unset")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(Arg::with_name("argv0").long("argv0").takes_value(true))
+                .arg(Arg::with_name("chdir").long("chdir").takes_value(true))
+                .arg(Arg::with_name("umask").long("umask").takes_value(true))
+                .arg(
+                    Arg::with_name("keep_fd")
+                        .long("keep-fd")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .args(&privilege_args())
+                .args(&sandbox_args())
+                .arg(
+                    Arg::with_name("command")
+                        .required(true)
+                        .multiple(true)
+                        .last(true),
+                ),
         )
         .get_matches();
 
-    let port = matches.value_of("port").unwrap_or("1337");
-    let env = matches.value_of("env");
-    let gdb = matches.is_present("gdb");
-    let program = matches.value_of("program").unwrap();
-    let gdb_args = matches.value_of("gdb_args");
-
-    let env_vars: Vec<(&str, &str)> = match env {
-        None => vec![],
-        Some(env) => env
-            .split(';')
-            .map(|x| {
-                let mut split = x.split('=').map(|x| return x.trim());
-                if split.clone().count() != 2 {
-                    panic!("Invalid environment variable passed");
+    if let Some(matches) = matches.subcommand_matches("exec-wrapper") {
+        let rlimits = values_t!(matches, "rlimit", Rlimit).unwrap_or_default();
+        let mut command = matches.values_of_os("command").unwrap();
+        let mut program = PathBuf::from(command.next().unwrap());
+        Environment {
+            clean: matches.is_present("clean_env"),
+            vars: matches
+                .values_of_os("env")
+                .into_iter()
+                .flatten()
+                .map(|x| payload::parse_env(x).unwrap())
+                .collect(),
+            unset: matches
+                .values_of_os("unset")
+                .into_iter()
+                .flatten()
+                .map(|x| payload::decode(x).unwrap())
+                .collect(),
+        }
+        .apply();
+        let argv0 = matches
+            .value_of_os("argv0")
+            .map(|x| payload::decode(x).unwrap());
+
+        if let Some(credentials) = parse_credentials(matches) {
+            credentials
+                .apply()
+                .expect("Failed to drop privileges, refusing to continue");
+        }
+        let sandbox = parse_sandbox(matches).unwrap_or_default();
+        // The policy might not be reachable anymore from inside the sandbox.
+        let policy = sandbox
+            .seccomp
+            .as_ref()
+            .map(|(path, action)| Policy::load(path, *action).unwrap());
+
+        // gdb hands us the program as found on the host.
+        program = sandbox.resolve(&program);
+        sandbox.enter().expect("Failed to set up the sandbox");
+        rlimit::apply(&rlimits).expect("Failed to apply resource limits");
+        // Only now, the sandbox might have changed the root directory.
+        if let Some(dir) = matches.value_of_os("chdir") {
+            let dir = payload::decode(dir).unwrap();
+            std::env::set_current_dir(&dir).unwrap_or_else(|e| {
+                panic!("Failed to change into {}: {}", dir.to_string_lossy(), e)
+            });
+        }
+        if let Some(umask) = matches.value_of("umask") {
+            unsafe { libc::umask(parse_umask(umask).unwrap()) };
+        }
+        let keep_fds = values_t!(matches, "keep_fd", RawFd).unwrap_or_default();
+        fds::keep_only(&keep_fds).expect("Failed to close file descriptors");
+        let err = match policy {
+            Some(policy) => {
+                let argv0 = argv0.unwrap_or_else(|| program.clone().into_os_string());
+                let args: Vec<OsString> = std::iter::once(argv0)
+                    .chain(command.map(OsString::from))
+                    .collect();
+                policy.exec(&program, &args)
+            }
+            None => {
+                let mut cmd = Command::new(program);
+                if let Some(argv0) = argv0 {
+                    cmd.arg0(argv0);
                 }
+                cmd.args(command).exec()
+            }
+        };
+        panic!("Failed to execute the inferior: {}", err);
+    }
 
-                let var = split.next().unwrap();
-                let value = split.next().unwrap();
-                (var, value)
-            })
-            .collect(),
+    if let Some(matches) = matches.subcommand_matches("replay") {
+        let mut target = parse_target(matches);
+        if target.pty.is_none() && target.relay.is_none() {
+            target.relay = Some(RelayMode::Pipe);
+        }
+        let transcript = Path::new(matches.value_of("transcript").unwrap());
+        let timeout = value_t!(matches, "wait_timeout", f64).unwrap();
+        let wait = matches.is_present("wait");
+
+        let matched =
+            replay::replay(transcript, &target, wait, Duration::from_secs_f64(timeout)).unwrap();
+        std::process::exit(if matched { 0 } else { 1 });
+    }
+
+    let port = value_t!(matches, "port", u16).unwrap_or(1337);
+    let bind = parse_bind_addr(matches.value_of("bind").unwrap_or("127.0.0.1")).unwrap();
+    let endpoint = match (matches.value_of("unix"), matches.value_of("connect")) {
+        (Some(path), _) => Endpoint::Unix(path.to_string()),
+        (_, Some(addr)) => Endpoint::Connect(addr.to_string()),
+        _ => Endpoint::Tcp(SocketAddr::new(bind, port)),
+    };
+    let forever = matches.is_present("forever");
+    let ready = Readiness {
+        json: matches.is_present("ready_json"),
+        file: matches.value_of("ready_file"),
+        fd: value_t!(matches, "ready_fd", i32).ok(),
     };
 
-    run(program, port, env_vars, gdb, gdb_args).unwrap()
+    let record = matches.value_of("record").map(|dir| {
+        (
+            Path::new(dir),
+            value_t!(matches, "record_format", RecordFormat).unwrap(),
+        )
+    });
+    let mut target = parse_target(&matches);
+    target.bind = bind;
+    target.record = record;
+    target.conditions = parse_conditions(&matches);
+    target.limits = Limits {
+        timeout: value_t!(matches, "timeout", f64)
+            .ok()
+            .map(Duration::from_secs_f64),
+        idle_timeout: value_t!(matches, "idle_timeout", f64)
+            .ok()
+            .map(Duration::from_secs_f64),
+        grace: Duration::from_secs_f64(value_t!(matches, "kill_grace", f64).unwrap()),
+    };
+    let needs_relay =
+        record.is_some() || target.conditions.is_some() || target.limits.idle_timeout.is_some();
+    if needs_relay && !target.relayed() {
+        target.relay = Some(RelayMode::Pipe);
+    }
+
+    run(&target, &endpoint, forever, &ready).unwrap()
 }
@@ -0,0 +1,189 @@
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::Path;
+
+fn hex_digit(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|x| x as u8)
+}
+
+/// Decodes `\xNN` escapes and `\\` into the bytes they stand for, any other
+/// backslash is kept as is.
+pub fn decode(value: &OsStr) -> Result<OsString, String> {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match (bytes[i], bytes.get(i + 1)) {
+            (b'\\', Some(b'\\')) => {
+                out.push(b'\\');
+                i += 2;
+            }
+            (b'\\', Some(b'x')) => {
+                let byte = match (bytes.get(i + 2), bytes.get(i + 3)) {
+                    (Some(&high), Some(&low)) => hex_digit(high)
+                        .zip(hex_digit(low))
+                        .map(|(high, low)| high << 4 | low),
+                    _ => None,
+                };
+                match byte {
+                    Some(byte) => out.push(byte),
+                    None => {
+                        return Err(format!(
+                            "invalid escape, expected \\xNN: {}",
+                            value.to_string_lossy()
+                        ))
+                    }
+                }
+                i += 4;
+            }
+            (c, _) => {
+                out.push(c);
+                i += 1;
+            }
+        }
+    }
+
+    if out.contains(&0) {
+        return Err(format!(
+            "arguments and environment variables cannot contain NUL bytes: {}",
+            value.to_string_lossy()
+        ));
+    }
+    Ok(OsString::from_vec(out))
+}
+
+/// Escapes everything but printable ASCII so that `decode` gives back the
+/// original value.
+pub fn encode(value: &OsStr) -> String {
+    value
+        .as_bytes()
+        .iter()
+        .map(|&c| match c {
+            b'\\' => "\\\\".to_string(),
+            c if c.is_ascii_graphic() || c == b' ' => (c as char).to_string(),
+            c => format!("\\x{:02x}", c),
+        })
+        .collect()
+}
+
+/// Splits `KEY=VALUE` on the first `=`, so that values may contain more.
+pub fn split_env(var: &OsStr) -> Result<(OsString, OsString), String> {
+    let bytes = var.as_bytes();
+    match bytes.iter().position(|&c| c == b'=') {
+        Some(i) if i > 0 => Ok((
+            OsStr::from_bytes(&bytes[..i]).to_owned(),
+            OsStr::from_bytes(&bytes[i + 1..]).to_owned(),
+        )),
+        _ => Err(format!(
+            "invalid environment variable, expected KEY=VALUE: {}",
+            var.to_string_lossy()
+        )),
+    }
+}
+
+/// Parses `KEY=VALUE` as given on the command line, with escapes.
+pub fn parse_env(var: &OsStr) -> Result<(OsString, OsString), String> {
+    split_env(&decode(var)?)
+}
+
+/// Reads NUL separated entries, the format of /proc/PID/cmdline and
+/// /proc/PID/environ. A file without any NUL bytes is a single entry.
+pub fn read_entries(path: &Path) -> std::io::Result<Vec<OsString>> {
+    let data = std::fs::read(path)?;
+    let data = data.strip_suffix(b"\0").unwrap_or(&data);
+    if data.is_empty() {
+        return Ok(Vec::new());
+    }
+    Ok(data
+        .split(|&c| c == 0)
+        .map(|x| OsStr::from_bytes(x).to_owned())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decoded(value: &str) -> Result<Vec<u8>, String> {
+        decode(OsStr::new(value)).map(OsString::into_vec)
+    }
+
+    #[test]
+    fn decode_escapes() {
+        assert_eq!(decoded(r"a\x41\x7f\xff").unwrap(), b"aA\x7f\xff");
+        assert_eq!(decoded(r"\xfF\xAb").unwrap(), b"\xff\xab");
+        assert_eq!(decoded(r"\\x41").unwrap(), b"\\x41");
+        assert_eq!(decoded(r"\n\").unwrap(), b"\\n\\");
+        assert!(decoded(r"\x4").is_err());
+        assert!(decoded(r"\xg1").is_err());
+    }
+
+    #[test]
+    fn decode_rejects_nul() {
+        assert!(decoded(r"a\x00b").is_err());
+    }
+
+    #[test]
+    fn encode_round_trips() {
+        let values: &[&[u8]] = &[
+            b"",
+            b"plain text",
+            b"\\x41 is not an escape",
+            b"trailing \\",
+            b"\x01\x1b[0m\t\n\x7f\x80\xff",
+            b"'quotes' \"and\" = : $HOME",
+        ];
+        for &value in values {
+            let value = OsStr::from_bytes(value);
+            let encoded = encode(value);
+            assert!(encoded.bytes().all(|c| c.is_ascii_graphic() || c == b' '));
+            assert_eq!(decode(OsStr::new(&encoded)).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn split_env_on_first_equals() {
+        let split = |var: &str| split_env(OsStr::new(var));
+        assert_eq!(
+            split("KEY=a=b").unwrap(),
+            (OsString::from("KEY"), OsString::from("a=b"))
+        );
+        assert_eq!(
+            split("KEY=").unwrap(),
+            (OsString::from("KEY"), OsString::new())
+        );
+        assert!(split("KEY").is_err());
+        assert!(split("=value").is_err());
+    }
+
+    #[test]
+    fn parse_env_decodes_first() {
+        assert_eq!(
+            parse_env(OsStr::new(r"K\x3dEY=\xff")).unwrap(),
+            (OsString::from("K"), OsString::from_vec(b"EY=\xff".to_vec()))
+        );
+    }
+
+    #[test]
+    fn read_entries_splits_on_nul() {
+        let path = std::env::temp_dir().join(format!("netpwn-entries-{}", std::process::id()));
+        let read = |data: &[u8]| {
+            std::fs::write(&path, data).unwrap();
+            let entries = read_entries(&path).unwrap();
+            entries
+                .into_iter()
+                .map(OsString::into_vec)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(read(b"A=1\0B=2\0"), [b"A=1".to_vec(), b"B=2".to_vec()]);
+        assert_eq!(
+            read(b"a\0\0b"),
+            [b"a".to_vec(), b"".to_vec(), b"b".to_vec()]
+        );
+        assert_eq!(read(b"one entry\n"), [b"one entry\n".to_vec()]);
+        assert!(read(b"").is_empty());
+        assert!(read(b"\0").is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
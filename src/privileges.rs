@@ -0,0 +1,88 @@
+use crate::sys::check;
+use std::ffi::CString;
+
+/// The user and groups the target runs as instead of whoever started netpwn.
+#[derive(Clone)]
+pub struct Credentials {
+    pub uid: Option<libc::uid_t>,
+    pub gid: Option<libc::gid_t>,
+    pub groups: Vec<libc::gid_t>,
+}
+
+/// Looks up a user by name or id, returning its uid and, if the user is
+/// known, its primary group.
+pub fn lookup_user(user: &str) -> Result<(libc::uid_t, Option<libc::gid_t>), String> {
+    let passwd = match user.parse::<libc::uid_t>() {
+        Ok(uid) => unsafe { libc::getpwuid(uid) },
+        Err(_) => {
+            let name = CString::new(user).map_err(|_| format!("invalid user: {}", user))?;
+            unsafe { libc::getpwnam(name.as_ptr()) }
+        }
+    };
+
+    match (unsafe { passwd.as_ref() }, user.parse()) {
+        (Some(passwd), _) => Ok((passwd.pw_uid, Some(passwd.pw_gid))),
+        (None, Ok(uid)) => Ok((uid, None)),
+        (None, Err(_)) => Err(format!("no such user: {}", user)),
+    }
+}
+
+/// Looks up a group by name or id.
+pub fn lookup_group(group: &str) -> Result<libc::gid_t, String> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+
+    let name = CString::new(group).map_err(|_| format!("invalid group: {}", group))?;
+    match unsafe { libc::getgrnam(name.as_ptr()).as_ref() } {
+        Some(entry) => Ok(entry.gr_gid),
+        None => Err(format!("no such group: {}", group)),
+    }
+}
+
+impl Credentials {
+    /// Arguments that make the `exec-wrapper` subcommand drop to the same
+    /// credentials.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(uid) = self.uid {
+            args.push(format!("--user={}", uid));
+        }
+        if let Some(gid) = self.gid {
+            args.push(format!("--group={}", gid));
+        }
+        let groups: Vec<String> = self.groups.iter().map(|x| x.to_string()).collect();
+        args.push(format!("--supplementary-groups={}", groups.join(",")));
+        args
+    }
+
+    /// Switches the current process over for good. Fork-safe, see
+    /// [`crate::sys`].
+    pub fn apply(&self) -> std::io::Result<()> {
+        // Groups have to go first, changing them needs the privileges that
+        // setuid takes away.
+        check(unsafe { libc::setgroups(self.groups.len(), self.groups.as_ptr()) })?;
+        if let Some(gid) = self.gid {
+            check(unsafe { libc::setresgid(gid, gid, gid) })?;
+        }
+        if let Some(uid) = self.uid {
+            check(unsafe { libc::setresuid(uid, uid, uid) })?;
+
+            // Make sure there really is no way back before running anything.
+            if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    "privileges could be regained after dropping them",
+                ));
+            }
+
+            // Changing ids made /proc/self inaccessible to ourselves, which
+            // would keep the sandbox from writing its id maps. Nothing is left
+            // to protect at this point.
+            check(unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 1, 0, 0, 0) })?;
+        }
+
+        check(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) })?;
+        Ok(())
+    }
+}
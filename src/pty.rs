@@ -0,0 +1,101 @@
+use crate::sys::check;
+use std::ffi::CStr;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+pub struct PtyOptions {
+    pub raw: bool,
+    pub echo: bool,
+    pub cols: u16,
+    pub rows: u16,
+}
+
+pub struct Pty {
+    pub master: OwnedFd,
+    pub slave: OwnedFd,
+    pub path: String,
+}
+
+/// Parses a window size given as `COLSxROWS`, e.g. `80x24`.
+pub fn parse_size(size: &str) -> Result<(u16, u16), String> {
+    let error = || format!("invalid window size, expected COLSxROWS: {}", size);
+    let (cols, rows) = size.split_once('x').ok_or_else(error)?;
+    let cols = cols.parse().map_err(|_| error())?;
+    let rows = rows.parse().map_err(|_| error())?;
+    Ok((cols, rows))
+}
+
+impl Pty {
+    pub fn open(options: &PtyOptions) -> std::io::Result<Pty> {
+        let master =
+            check(unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC) })?;
+        let master = unsafe { OwnedFd::from_raw_fd(master) };
+        check(unsafe { libc::grantpt(master.as_raw_fd()) })?;
+        check(unsafe { libc::unlockpt(master.as_raw_fd()) })?;
+
+        let mut buf = [0 as libc::c_char; 64];
+        let ret = unsafe { libc::ptsname_r(master.as_raw_fd(), buf.as_mut_ptr(), buf.len()) };
+        if ret != 0 {
+            return Err(std::io::Error::from_raw_os_error(ret));
+        }
+        let path = unsafe { CStr::from_ptr(buf.as_ptr()) };
+
+        let slave = check(unsafe {
+            libc::open(
+                path.as_ptr(),
+                libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC,
+            )
+        })?;
+        let slave = unsafe { OwnedFd::from_raw_fd(slave) };
+
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        check(unsafe { libc::tcgetattr(slave.as_raw_fd(), &mut termios) })?;
+        if options.raw {
+            unsafe { libc::cfmakeraw(&mut termios) };
+        }
+        if !options.echo {
+            termios.c_lflag &= !(libc::ECHO | libc::ECHONL);
+        }
+        check(unsafe { libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) })?;
+
+        let winsize = libc::winsize {
+            ws_row: options.rows,
+            ws_col: options.cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        check(unsafe { libc::ioctl(slave.as_raw_fd(), libc::TIOCSWINSZ, &winsize) })?;
+
+        Ok(Pty {
+            master,
+            slave,
+            path: path.to_string_lossy().into_owned(),
+        })
+    }
+
+    /// Hands the terminal over to `uid`, for targets opening it by path after
+    /// dropping privileges.
+    pub fn set_owner(&self, uid: libc::uid_t) -> std::io::Result<()> {
+        check(unsafe { libc::fchown(self.slave.as_raw_fd(), uid, libc::gid_t::MAX) })?;
+        Ok(())
+    }
+
+    /// Hands the slave to the command as its stdio and makes it the
+    /// controlling terminal of a new session.
+    pub fn attach(&self, cmd: &mut Command) -> std::io::Result<()> {
+        cmd.stdin(self.slave.try_clone()?)
+            .stdout(self.slave.try_clone()?)
+            .stderr(self.slave.try_clone()?);
+
+        unsafe {
+            cmd.pre_exec(|| {
+                check(libc::setsid())?;
+                check(libc::ioctl(0, libc::TIOCSCTTY, 0))?;
+                Ok(())
+            });
+        }
+
+        Ok(())
+    }
+}
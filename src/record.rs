@@ -0,0 +1,364 @@
+use crate::json::{self, json_string, Value};
+use crate::relay::Direction;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::ExitStatus;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum RecordFormat {
+    Json,
+    Hexdump,
+}
+
+impl std::str::FromStr for RecordFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<RecordFormat, String> {
+        match format {
+            "json" => Ok(RecordFormat::Json),
+            "hexdump" => Ok(RecordFormat::Hexdump),
+            _ => Err(format!("invalid record format: {}", format)),
+        }
+    }
+}
+
+impl Direction {
+    fn name(self) -> &'static str {
+        match self {
+            Direction::ClientToTarget => "client_to_target",
+            Direction::TargetToClient => "target_to_client",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Direction> {
+        match name {
+            "client_to_target" => Some(Direction::ClientToTarget),
+            "target_to_client" => Some(Direction::TargetToClient),
+            _ => None,
+        }
+    }
+}
+
+/// Whose exit an exit event records. Without a way to learn the status of
+/// the target, only the one of gdb is known.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Process {
+    Target,
+    Gdb,
+}
+
+impl Process {
+    fn name(self) -> &'static str {
+        match self {
+            Process::Target => "target",
+            Process::Gdb => "gdb",
+        }
+    }
+}
+
+/// An event read back from a JSON transcript.
+pub enum Entry {
+    Data(Direction, Vec<u8>),
+    Exit {
+        code: Option<i32>,
+        signal: Option<i32>,
+    },
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Decodes padded base64 as `base64_encode` writes it.
+fn base64_decode(data: &str) -> Option<Vec<u8>> {
+    if !data.len().is_multiple_of(4) {
+        return None;
+    }
+    // Padding only ever ends the last group.
+    let padding = data.bytes().rev().take_while(|&c| c == b'=').count();
+    if padding > 2 {
+        return None;
+    }
+
+    let mut out = Vec::with_capacity(data.len() / 4 * 3);
+    let mut n = 0u32;
+    let mut bits = 0;
+    for c in data[..data.len() - padding].bytes() {
+        let value = BASE64_ALPHABET.iter().position(|&x| x == c)? as u32;
+        n = n << 6 | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((n >> bits) as u8);
+        }
+    }
+    // Whatever is left must be the zero bits the padding fills up.
+    if n & ((1 << bits) - 1) != 0 {
+        return None;
+    }
+    Some(out)
+}
+
+/// Formats `data` like `hexdump -C`, numbering lines starting at `offset`.
+pub fn hexdump(data: &[u8], offset: usize) -> String {
+    let mut out = String::new();
+    for (i, line) in data.chunks(16).enumerate() {
+        let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = line
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        out.push_str(&format!(
+            "{:08x}  {:<47}  |{}|\n",
+            offset + i * 16,
+            hex.join(" "),
+            ascii
+        ));
+    }
+    out
+}
+
+fn unix_time() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs_f64())
+        .unwrap_or(0.0)
+}
+
+/// Writes a transcript of everything exchanged during a single session.
+pub struct Recorder {
+    file: File,
+    format: RecordFormat,
+    start: Instant,
+}
+
+impl Recorder {
+    pub fn create(
+        dir: &Path,
+        format: RecordFormat,
+        peer: &str,
+        program: &str,
+        pid: u32,
+    ) -> std::io::Result<Recorder> {
+        std::fs::create_dir_all(dir)?;
+
+        let started = unix_time();
+        let extension = match format {
+            RecordFormat::Json => "jsonl",
+            RecordFormat::Hexdump => "txt",
+        };
+        let path = dir.join(format!("{:.6}-{}.{}", started, pid, extension));
+
+        let mut recorder = Recorder {
+            file: File::create(path)?,
+            format,
+            start: Instant::now(),
+        };
+
+        let header = match format {
+            RecordFormat::Json => format!(
+                "{{\"type\":\"start\",\"time\":{:.6},\"peer\":{},\"program\":{},\"pid\":{}}}\n",
+                started,
+                json_string(peer),
+                json_string(program),
+                pid
+            ),
+            RecordFormat::Hexdump => format!(
+                "# session with {} started at {:.6}, pid {} ({})\n",
+                peer, started, pid, program
+            ),
+        };
+        recorder.write(&header)?;
+
+        Ok(recorder)
+    }
+
+    fn write(&mut self, entry: &str) -> std::io::Result<()> {
+        // Every entry goes out with a single write so that the transcript
+        // stays readable even if netpwn gets killed mid-session.
+        self.file.write_all(entry.as_bytes())
+    }
+
+    pub fn data(&mut self, direction: Direction, data: &[u8]) -> std::io::Result<()> {
+        let time = self.start.elapsed().as_secs_f64();
+        let entry = match self.format {
+            RecordFormat::Json => format!(
+                "{{\"type\":\"data\",\"time\":{:.6},\"direction\":\"{}\",\"data\":\"{}\"}}\n",
+                time,
+                direction.name(),
+                base64_encode(data)
+            ),
+            RecordFormat::Hexdump => {
+                let arrow = match direction {
+                    Direction::ClientToTarget => "client -> target",
+                    Direction::TargetToClient => "target -> client",
+                };
+                format!(
+                    "[{:12.6}] {}, {} bytes\n{}",
+                    time,
+                    arrow,
+                    data.len(),
+                    hexdump(data, 0)
+                )
+            }
+        };
+        self.write(&entry)
+    }
+
+    /// Records how `process`, either the target or the debugger it runs
+    /// under, exited.
+    pub fn exit(&mut self, process: Process, status: ExitStatus) -> std::io::Result<()> {
+        let time = self.start.elapsed().as_secs_f64();
+        let entry = match self.format {
+            RecordFormat::Json => {
+                let status = match (status.code(), status.signal()) {
+                    (Some(code), _) => format!("\"code\":{}", code),
+                    (_, Some(signal)) => format!("\"signal\":{}", signal),
+                    _ => "\"code\":null".to_string(),
+                };
+                format!(
+                    "{{\"type\":\"exit\",\"time\":{:.6},\"process\":\"{}\",{}}}\n",
+                    time,
+                    process.name(),
+                    status
+                )
+            }
+            RecordFormat::Hexdump => {
+                format!("[{:12.6}] {} {}\n", time, process.name(), status)
+            }
+        };
+        self.write(&entry)
+    }
+}
+
+/// Reads the data and exit events of a transcript written in the JSON format.
+pub fn read_transcript(path: &Path) -> std::io::Result<Vec<Entry>> {
+    let invalid = |line: usize, error: String| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{}:{}: {}", path.display(), line, error),
+        )
+    };
+
+    let mut entries = Vec::new();
+    for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let fields = json::parse_object(&line).map_err(|e| invalid(i + 1, e))?;
+        let field = |name: &str| fields.iter().find(|x| x.0 == name).map(|x| &x.1);
+        let number = |name: &str| match field(name) {
+            Some(Value::Number(n)) => Some(*n as i32),
+            _ => None,
+        };
+
+        match field("type") {
+            Some(Value::String(kind)) if kind == "data" => {
+                let direction = match field("direction") {
+                    Some(Value::String(name)) => Direction::from_name(name),
+                    _ => None,
+                };
+                let data = match field("data") {
+                    Some(Value::String(data)) => base64_decode(data),
+                    _ => None,
+                };
+                match (direction, data) {
+                    (Some(direction), Some(data)) => entries.push(Entry::Data(direction, data)),
+                    _ => return Err(invalid(i + 1, "malformed data entry".to_string())),
+                }
+            }
+            // Transcripts from before the field was added only had targets.
+            Some(Value::String(kind))
+                if kind == "exit"
+                    && !matches!(field("process"), Some(Value::String(x)) if x != "target") =>
+            {
+                entries.push(Entry::Exit {
+                    code: number("code"),
+                    signal: number("signal"),
+                })
+            }
+            Some(Value::String(_)) => {}
+            _ => return Err(invalid(i + 1, "missing entry type".to_string())),
+        }
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_known_values() {
+        let cases = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for &(plain, encoded) in &cases {
+            assert_eq!(base64_encode(plain.as_bytes()), encoded);
+            assert_eq!(base64_decode(encoded).unwrap(), plain.as_bytes());
+        }
+    }
+
+    #[test]
+    fn base64_round_trips() {
+        let data: Vec<u8> = (0..=255).collect();
+        for len in 0..data.len() {
+            let encoded = base64_encode(&data[..len]);
+            assert_eq!(base64_decode(&encoded).unwrap(), &data[..len]);
+        }
+    }
+
+    #[test]
+    fn base64_rejects_malformed_input() {
+        for &encoded in &[
+            "Zg", "Zg=", "Z===", "====", "Zg==Zm9v", "Zm=v", "Zh==", "Zm9=", "Zm9v!A==", "Zm9v\n",
+        ] {
+            assert_eq!(base64_decode(encoded), None, "{}", encoded);
+        }
+    }
+
+    #[test]
+    fn hexdump_formats_like_hexdump_c() {
+        assert_eq!(hexdump(b"", 0), "");
+        assert_eq!(
+            hexdump(b"hello, world\n\x00\x7f\xffAB", 0x20),
+            "00000020  68 65 6c 6c 6f 2c 20 77 6f 72 6c 64 0a 00 7f ff  |hello, world....|\n\
+             00000030  41 42                                            |AB|\n"
+        );
+    }
+}
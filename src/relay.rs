@@ -0,0 +1,432 @@
+use crate::limits::{Limit, Limits};
+use crate::record::Recorder;
+use crate::shaping::{NetworkConditions, ShapedWriter, Shaping};
+use crate::sys::check;
+use std::fs::File;
+use std::io::prelude::*;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::process::Command;
+use std::time::Instant;
+
+const BUFFER_SIZE: usize = 4096;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum RelayMode {
+    Pipe,
+    SocketPair,
+}
+
+impl std::str::FromStr for RelayMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<RelayMode, String> {
+        match mode {
+            "pipe" => Ok(RelayMode::Pipe),
+            "socketpair" => Ok(RelayMode::SocketPair),
+            _ => Err(format!("invalid relay mode: {}", mode)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Direction {
+    ClientToTarget,
+    TargetToClient,
+}
+
+/// Our side of the target's stdio while relaying.
+pub struct TargetIo {
+    input: Option<File>,
+    output: File,
+    /// Whether the session ends as soon as the client hangs up instead of
+    /// only closing the target's input.
+    hangup: bool,
+}
+
+impl TargetIo {
+    pub fn pty(master: OwnedFd) -> std::io::Result<TargetIo> {
+        Ok(TargetIo {
+            input: Some(File::from(master.try_clone()?)),
+            output: File::from(master),
+            hangup: true,
+        })
+    }
+
+    /// Creates a connected socket pair and returns the end meant for the
+    /// target together with ours.
+    pub fn socketpair() -> std::io::Result<(OwnedFd, TargetIo)> {
+        let mut fds = [0; 2];
+        check(unsafe {
+            libc::socketpair(
+                libc::AF_UNIX,
+                libc::SOCK_STREAM | libc::SOCK_CLOEXEC,
+                0,
+                fds.as_mut_ptr(),
+            )
+        })?;
+        let theirs = unsafe { OwnedFd::from_raw_fd(fds[0]) };
+        let ours = unsafe { OwnedFd::from_raw_fd(fds[1]) };
+
+        let io = TargetIo {
+            input: Some(File::from(ours.try_clone()?)),
+            output: File::from(ours),
+            hangup: false,
+        };
+        Ok((theirs, io))
+    }
+
+    /// Connects the command's stdin to one pipe and its stdout and stderr to
+    /// another one.
+    pub fn pipes(cmd: &mut Command) -> std::io::Result<TargetIo> {
+        let mut stdin = [0; 2];
+        let mut stdout = [0; 2];
+        check(unsafe { libc::pipe2(stdin.as_mut_ptr(), libc::O_CLOEXEC) })?;
+        let (stdin_read, stdin_write) = unsafe {
+            (
+                OwnedFd::from_raw_fd(stdin[0]),
+                OwnedFd::from_raw_fd(stdin[1]),
+            )
+        };
+        check(unsafe { libc::pipe2(stdout.as_mut_ptr(), libc::O_CLOEXEC) })?;
+        let (stdout_read, stdout_write) = unsafe {
+            (
+                OwnedFd::from_raw_fd(stdout[0]),
+                OwnedFd::from_raw_fd(stdout[1]),
+            )
+        };
+
+        cmd.stdin(stdin_read)
+            .stdout(stdout_write.try_clone()?)
+            .stderr(stdout_write);
+
+        Ok(TargetIo {
+            input: Some(File::from(stdin_write)),
+            output: File::from(stdout_read),
+            hangup: false,
+        })
+    }
+}
+
+impl TargetIo {
+    pub fn input(&self) -> Option<&File> {
+        self.input.as_ref()
+    }
+
+    pub fn output(&self) -> &File {
+        &self.output
+    }
+
+    /// Lets the target see the end of its input.
+    pub fn close_input(&mut self) {
+        if let Some(input) = self.input.take() {
+            // A socket pair stays open as long as our output end does.
+            unsafe { libc::shutdown(input.as_raw_fd(), libc::SHUT_WR) };
+        }
+    }
+}
+
+/// Reads whatever is available on `from`, returns `None` once it reached its
+/// end.
+pub fn receive(mut from: &File, buf: &mut [u8]) -> Option<usize> {
+    loop {
+        match from.read(buf) {
+            Ok(0) => return None,
+            Ok(n) => return Some(n),
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            // A pty master reports EIO once the last slave has been closed.
+            Err(_) => return None,
+        }
+    }
+}
+
+/// Writes as much of `data` as `to` takes right away. The flag is only set
+/// for the write, a pty shares it with the side we read from.
+fn write_nonblocking(mut to: &File, data: &[u8]) -> std::io::Result<usize> {
+    let fd = to.as_raw_fd();
+    let flags = check(unsafe { libc::fcntl(fd, libc::F_GETFL) })?;
+    check(unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) })?;
+    let result = to.write(data);
+    check(unsafe { libc::fcntl(fd, libc::F_SETFL, flags) })?;
+    result
+}
+
+/// Where data read from one side ends up.
+enum Sink {
+    /// Data the destination did not take yet waits in `pending`, the stream
+    /// ends once it is delivered if `closing` is set.
+    Direct {
+        to: File,
+        pending: Vec<u8>,
+        closing: bool,
+    },
+    Shaped(ShapedWriter),
+    /// A shaped stream that ended, still delivering what was queued.
+    Draining(ShapedWriter),
+    Closed,
+}
+
+impl Sink {
+    fn new(to: File, shaping: Option<Shaping>, seed: u64) -> Sink {
+        match shaping {
+            Some(shaping) => Sink::Shaped(ShapedWriter::spawn(to, shaping, seed)),
+            None => Sink::Direct {
+                to,
+                pending: Vec::new(),
+                closing: false,
+            },
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        match self {
+            Sink::Direct { closing, .. } => !closing,
+            Sink::Shaped(_) => true,
+            Sink::Draining(_) | Sink::Closed => false,
+        }
+    }
+
+    /// The descriptor to wait on until the destination takes pending data.
+    fn blocked_on(&self) -> Option<RawFd> {
+        match self {
+            Sink::Direct { to, pending, .. } if !pending.is_empty() => Some(to.as_raw_fd()),
+            _ => None,
+        }
+    }
+
+    /// Returns false once the destination went away.
+    fn write(&mut self, data: &[u8]) -> bool {
+        match self {
+            Sink::Direct { pending, .. } => {
+                pending.extend_from_slice(data);
+                self.flush()
+            }
+            Sink::Shaped(writer) => writer.write(data),
+            Sink::Draining(_) | Sink::Closed => false,
+        }
+    }
+
+    /// Hands the destination as much pending data as it takes without
+    /// blocking. Returns false once it went away.
+    fn flush(&mut self) -> bool {
+        if let Sink::Direct { to, pending, .. } = self {
+            while !pending.is_empty() {
+                match write_nonblocking(to, pending) {
+                    Ok(n) => {
+                        pending.drain(..n);
+                    }
+                    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return true,
+                    Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                    Err(_) => {
+                        *self = Sink::Closed;
+                        return false;
+                    }
+                }
+            }
+        }
+        if let Sink::Direct { closing: true, .. } = self {
+            self.close();
+        }
+        true
+    }
+
+    /// Passes on the end of the stream, after everything written so far.
+    fn close(&mut self) {
+        match std::mem::replace(self, Sink::Closed) {
+            Sink::Direct { to, pending, .. } if !pending.is_empty() => {
+                *self = Sink::Direct {
+                    to,
+                    pending,
+                    closing: true,
+                };
+            }
+            Sink::Direct { to, .. } => unsafe {
+                libc::shutdown(to.as_raw_fd(), libc::SHUT_WR);
+            },
+            Sink::Shaped(mut writer) => {
+                writer.close();
+                *self = Sink::Draining(writer);
+            }
+            sink => *self = sink,
+        }
+    }
+
+    /// Waits for queued data to be delivered, at most until `deadline`.
+    /// Returns false if it was not.
+    fn finish(self, deadline: Option<Instant>) -> bool {
+        match self {
+            Sink::Shaped(writer) | Sink::Draining(writer) => writer.join(deadline),
+            _ => true,
+        }
+    }
+}
+
+/// Shuttles data between the client and the target until the target is done
+/// talking or the client went away.
+///
+/// When the client closes its sending side only the target's input is
+/// closed, the target's remaining output is still delivered. Neither side
+/// holds up the other one or the limits by not reading.
+///
+/// Returns which limit ended the session early, if any.
+pub fn relay(
+    client: OwnedFd,
+    mut target: TargetIo,
+    mut recorder: Option<&mut Recorder>,
+    conditions: Option<&NetworkConditions>,
+    limits: &Limits,
+) -> std::io::Result<Option<Limit>> {
+    let client = File::from(client);
+    let mut buf = [0; BUFFER_SIZE];
+
+    let deadline = limits.timeout.map(|x| Instant::now() + x);
+    let mut last_activity = Instant::now();
+
+    let seed = conditions.map(|x| x.seed).unwrap_or_default();
+    let mut sinks = [
+        Sink::new(
+            target.input.take().unwrap(),
+            conditions.map(|x| x.client_to_target),
+            seed,
+        ),
+        Sink::new(
+            client.try_clone()?,
+            conditions.map(|x| x.target_to_client),
+            seed.rotate_left(32),
+        ),
+    ];
+    // Once either side is done only what is already queued gets delivered.
+    let mut draining = false;
+
+    let mut result = loop {
+        if draining && sinks.iter().all(|x| x.blocked_on().is_none()) {
+            break Ok(None);
+        }
+
+        // Nothing more is read from a side until the data read from it
+        // before got out.
+        let source = |sink: &Sink, fd: RawFd| {
+            if !draining && sink.is_open() && sink.blocked_on().is_none() {
+                fd
+            } else {
+                -1
+            }
+        };
+        let mut fds = [
+            libc::pollfd {
+                fd: source(&sinks[0], client.as_raw_fd()),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: source(&sinks[1], target.output.as_raw_fd()),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: sinks[0].blocked_on().unwrap_or(-1),
+                events: libc::POLLOUT,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: sinks[1].blocked_on().unwrap_or(-1),
+                events: libc::POLLOUT,
+                revents: 0,
+            },
+        ];
+
+        let limit = [
+            (deadline, Limit::Timeout),
+            (
+                limits.idle_timeout.map(|x| last_activity + x),
+                Limit::IdleTimeout,
+            ),
+        ]
+        .iter()
+        .filter_map(|(expiry, limit)| expiry.map(|x| (x, *limit)))
+        .min_by_key(|(expiry, _)| *expiry);
+
+        let timeout = match limit {
+            Some((expiry, limit)) => {
+                let timeout = expiry.saturating_duration_since(Instant::now());
+                if timeout.is_zero() {
+                    break Ok(Some(limit));
+                }
+                // Round up, polling for 0ms just to wake up again right away
+                // would make us spin.
+                timeout.as_millis().min(libc::c_int::MAX as u128 - 1) as libc::c_int + 1
+            }
+            None => -1,
+        };
+
+        let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+        if ret < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            break Err(err);
+        }
+
+        let mut done = false;
+        for (sink, pollfd) in sinks.iter_mut().zip(&fds[2..]) {
+            if pollfd.revents == 0 {
+                continue;
+            }
+            last_activity = Instant::now();
+            if !sink.flush() {
+                done = true;
+            }
+        }
+
+        for (i, direction) in [Direction::ClientToTarget, Direction::TargetToClient]
+            .iter()
+            .enumerate()
+        {
+            if fds[i].revents == 0 {
+                continue;
+            }
+
+            let from = match direction {
+                Direction::ClientToTarget => &client,
+                Direction::TargetToClient => &target.output,
+            };
+
+            match receive(from, &mut buf) {
+                Some(n) => {
+                    last_activity = Instant::now();
+                    if let Some(recorder) = recorder.as_mut() {
+                        recorder.data(*direction, &buf[..n])?;
+                    }
+                    if !sinks[i].write(&buf[..n]) {
+                        done = true;
+                    }
+                }
+                None if *direction == Direction::ClientToTarget && !target.hangup => {
+                    sinks[i].close();
+                }
+                None => done = true,
+            }
+        }
+
+        draining |= done;
+    };
+
+    let [mut to_target, mut to_client] = sinks;
+    if let Ok(None) = result {
+        // Deliver whatever is still queued up before hanging up on either
+        // side.
+        to_client.close();
+        let delivered = to_client.finish(deadline);
+        to_target.close();
+        if !(delivered && to_target.finish(deadline)) {
+            result = Ok(Some(Limit::Timeout));
+        }
+    }
+    if let Ok(Some(_)) = result {
+        // Writers stuck on a client that stopped reading give up once it
+        // is hung up on, the ones writing to the target once it is killed.
+        unsafe { libc::shutdown(client.as_raw_fd(), libc::SHUT_RDWR) };
+    }
+
+    result
+}
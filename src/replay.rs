@@ -0,0 +1,288 @@
+use crate::record::{self, hexdump, Entry};
+use crate::relay::{self, Direction, TargetIo};
+use crate::{spawn_relayed, Target};
+use std::io::prelude::*;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Number of bytes shown around a divergence.
+const CONTEXT: usize = 32;
+
+/// Converts a timeout for poll, which takes milliseconds as an int.
+fn poll_timeout(timeout: Duration) -> libc::c_int {
+    timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int
+}
+
+/// Reads the target's output until `want` bytes were received in total, the
+/// target closed its output or the deadline passed. Returns false on EOF.
+fn read_until(
+    output: &std::fs::File,
+    received: &mut Vec<u8>,
+    want: usize,
+    deadline: Instant,
+) -> std::io::Result<bool> {
+    let mut buf = [0; 4096];
+    while received.len() < want {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        if timeout == Duration::from_millis(0) {
+            return Ok(true);
+        }
+
+        let mut pollfd = libc::pollfd {
+            fd: output.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ret = unsafe { libc::poll(&mut pollfd, 1, poll_timeout(timeout)) };
+        if ret < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        if ret == 0 {
+            continue;
+        }
+
+        match relay::receive(output, &mut buf) {
+            Some(n) => received.extend_from_slice(&buf[..n]),
+            None => return Ok(false),
+        }
+    }
+
+    Ok(true)
+}
+
+fn set_nonblocking(file: &std::fs::File, nonblocking: bool) -> std::io::Result<()> {
+    let fd = file.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let flags = if nonblocking {
+        flags | libc::O_NONBLOCK
+    } else {
+        flags & !libc::O_NONBLOCK
+    };
+    if unsafe { libc::fcntl(fd, libc::F_SETFL, flags) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Writes `data` to the target while taking in whatever it outputs, as a
+/// target that is busy writing stops reading once the buffers in between are
+/// full. Returns false if the target did not take the data before the
+/// deadline or went away.
+fn write_draining(
+    io: &TargetIo,
+    data: &[u8],
+    received: &mut Vec<u8>,
+    deadline: Instant,
+) -> std::io::Result<bool> {
+    let input = match io.input() {
+        Some(input) => input,
+        None => return Ok(false),
+    };
+    // A pty or socket pair shares the flag with the output, which is only
+    // read once it polled readable.
+    set_nonblocking(input, true)?;
+    let result = write_polling(input, io.output(), data, received, deadline);
+    set_nonblocking(input, false)?;
+    result
+}
+
+fn write_polling(
+    mut input: &std::fs::File,
+    output: &std::fs::File,
+    mut data: &[u8],
+    received: &mut Vec<u8>,
+    deadline: Instant,
+) -> std::io::Result<bool> {
+    let mut buf = [0; 4096];
+    let mut output_open = true;
+    while !data.is_empty() {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        if timeout == Duration::from_millis(0) {
+            return Ok(false);
+        }
+
+        let mut pollfds = [
+            libc::pollfd {
+                fd: input.as_raw_fd(),
+                events: libc::POLLOUT,
+                revents: 0,
+            },
+            libc::pollfd {
+                // Negative descriptors are skipped.
+                fd: if output_open { output.as_raw_fd() } else { -1 },
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        let ret = unsafe { libc::poll(pollfds.as_mut_ptr(), 2, poll_timeout(timeout)) };
+        if ret < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+
+        if pollfds[1].revents != 0 {
+            match relay::receive(output, &mut buf) {
+                Some(n) => received.extend_from_slice(&buf[..n]),
+                None => output_open = false,
+            }
+        }
+        if pollfds[0].revents != 0 {
+            match input.write(data) {
+                Ok(n) => data = &data[n..],
+                Err(ref e)
+                    if e.kind() == std::io::ErrorKind::WouldBlock
+                        || e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(_) => return Ok(false),
+            }
+        }
+    }
+
+    Ok(true)
+}
+
+/// Returns the offset of the first byte where the received output differs
+/// from the expected one, only looking at the first `len` bytes.
+fn divergence(expected: &[u8], received: &[u8], len: usize) -> Option<usize> {
+    let expected = &expected[..std::cmp::min(len, expected.len())];
+    let received = &received[..std::cmp::min(len, received.len())];
+    match expected.iter().zip(received).position(|(a, b)| a != b) {
+        Some(offset) => Some(offset),
+        None if expected.len() != received.len() => {
+            Some(std::cmp::min(expected.len(), received.len()))
+        }
+        None => None,
+    }
+}
+
+fn report(expected: &[u8], received: &[u8], offset: usize, chunk: usize) {
+    let start = offset - offset % 16;
+    let context = |data: &[u8]| {
+        let data = &data[std::cmp::min(start, data.len())..];
+        hexdump(&data[..std::cmp::min(CONTEXT, data.len())], start)
+    };
+
+    eprintln!(
+        "output diverges at offset {:#x} before client chunk #{}",
+        offset, chunk
+    );
+    eprintln!("expected ({} bytes in total):", expected.len());
+    eprint!("{}", context(expected));
+    eprintln!("received ({} bytes in total):", received.len());
+    eprint!("{}", context(received));
+}
+
+/// Feeds the client side of a recorded session to a fresh target and
+/// compares the target's output against the recording. Returns whether the
+/// target behaved exactly as recorded.
+pub fn replay(
+    transcript: &Path,
+    target: &Target,
+    wait: bool,
+    timeout: Duration,
+) -> std::io::Result<bool> {
+    let entries = record::read_transcript(transcript)?;
+    let expected: Vec<u8> = entries
+        .iter()
+        .filter_map(|entry| match entry {
+            Entry::Data(Direction::TargetToClient, data) => Some(data.as_slice()),
+            _ => None,
+        })
+        .flatten()
+        .copied()
+        .collect();
+
+    let mut relayed = spawn_relayed(target)?;
+    let mut received = Vec::new();
+    let mut expected_len = 0;
+    let mut chunk = 0;
+
+    for entry in &entries {
+        let data = match entry {
+            Entry::Data(Direction::TargetToClient, data) => {
+                expected_len += data.len();
+                continue;
+            }
+            Entry::Data(Direction::ClientToTarget, data) => data,
+            Entry::Exit { .. } => continue,
+        };
+
+        if wait {
+            let deadline = Instant::now() + timeout;
+            read_until(relayed.io.output(), &mut received, expected_len, deadline)?;
+            if let Some(offset) = divergence(&expected, &received, expected_len) {
+                report(&expected, &received, offset, chunk);
+                let _ = relayed.child.kill();
+                let _ = relayed.child.wait();
+                return Ok(false);
+            }
+        }
+
+        let deadline = Instant::now() + timeout;
+        let sent = write_draining(&relayed.io, data, &mut received, deadline)?;
+        if !sent {
+            eprintln!("target stopped reading before client chunk #{}", chunk);
+            break;
+        }
+        chunk += 1;
+    }
+
+    relayed.io.close_input();
+    let deadline = Instant::now() + timeout;
+    read_until(relayed.io.output(), &mut received, usize::MAX, deadline)?;
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = relayed.child.try_wait()? {
+            break Some(status);
+        }
+        if Instant::now() >= deadline {
+            let _ = relayed.child.kill();
+            relayed.child.wait()?;
+            break None;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+
+    let mut matched = true;
+    if let Some(offset) = divergence(&expected, &received, usize::MAX) {
+        report(&expected, &received, offset, chunk);
+        matched = false;
+    }
+
+    let recorded = entries.iter().find_map(|entry| match entry {
+        Entry::Exit { code, signal } => Some((*code, *signal)),
+        _ => None,
+    });
+    match (recorded, status) {
+        (Some(recorded), Some(status)) if recorded != (status.code(), status.signal()) => {
+            eprintln!(
+                "target exited with {}, recorded code {:?} signal {:?}",
+                status, recorded.0, recorded.1
+            );
+            matched = false;
+        }
+        (Some(_), None) => {
+            eprintln!("target did not exit within {:?}", timeout);
+            matched = false;
+        }
+        _ => {}
+    }
+
+    if matched {
+        eprintln!("replay matches the recording");
+    }
+
+    Ok(matched)
+}
@@ -0,0 +1,145 @@
+use crate::sys::check;
+use std::fmt;
+
+const RESOURCES: &[(&str, libc::__rlimit_resource_t)] = &[
+    ("as", libc::RLIMIT_AS),
+    ("core", libc::RLIMIT_CORE),
+    ("cpu", libc::RLIMIT_CPU),
+    ("data", libc::RLIMIT_DATA),
+    ("fsize", libc::RLIMIT_FSIZE),
+    ("memlock", libc::RLIMIT_MEMLOCK),
+    ("nofile", libc::RLIMIT_NOFILE),
+    ("nproc", libc::RLIMIT_NPROC),
+    ("stack", libc::RLIMIT_STACK),
+];
+
+#[derive(Clone, Copy)]
+pub struct Rlimit {
+    name: &'static str,
+    resource: libc::__rlimit_resource_t,
+    soft: libc::rlim_t,
+    hard: libc::rlim_t,
+}
+
+/// Parses a limit like `1024`, `64M` or `unlimited`.
+fn parse_value(value: &str) -> Option<libc::rlim_t> {
+    if value == "unlimited" || value == "inf" {
+        return Some(libc::RLIM_INFINITY);
+    }
+
+    let (number, shift) = match value.char_indices().last()? {
+        (i, 'k') | (i, 'K') => (&value[..i], 10),
+        (i, 'm') | (i, 'M') => (&value[..i], 20),
+        (i, 'g') | (i, 'G') => (&value[..i], 30),
+        _ => (value, 0),
+    };
+    let number: libc::rlim_t = number.parse().ok()?;
+    number.checked_mul(1 << shift)
+}
+
+impl std::str::FromStr for Rlimit {
+    type Err = String;
+
+    /// Parses `NAME=SOFT[:HARD]`, the hard limit defaults to the soft one.
+    fn from_str(spec: &str) -> Result<Rlimit, String> {
+        let error = || {
+            format!(
+                "invalid resource limit, expected NAME=SOFT[:HARD]: {}",
+                spec
+            )
+        };
+        let (name, values) = spec.split_once('=').ok_or_else(error)?;
+        let &(name, resource) = RESOURCES
+            .iter()
+            .find(|x| x.0 == name)
+            .ok_or_else(|| format!("unknown resource limit: {}", name))?;
+
+        let (soft, hard) = match values.split_once(':') {
+            Some((soft, hard)) => (parse_value(soft), parse_value(hard)),
+            None => (parse_value(values), parse_value(values)),
+        };
+        match (soft, hard) {
+            (Some(soft), Some(hard)) if soft <= hard => Ok(Rlimit {
+                name,
+                resource,
+                soft,
+                hard,
+            }),
+            _ => Err(error()),
+        }
+    }
+}
+
+impl fmt::Display for Rlimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let value = |x| match x {
+            libc::RLIM_INFINITY => "unlimited".to_string(),
+            x => x.to_string(),
+        };
+        write!(f, "{}={}:{}", self.name, value(self.soft), value(self.hard))
+    }
+}
+
+/// Applies the limits to the current process. Fork-safe, see [`crate::sys`].
+pub fn apply(limits: &[Rlimit]) -> std::io::Result<()> {
+    for limit in limits {
+        let rlimit = libc::rlimit {
+            rlim_cur: limit.soft,
+            rlim_max: limit.hard,
+        };
+        check(unsafe { libc::setrlimit(limit.resource, &rlimit) })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(spec: &str) -> Result<(libc::rlim_t, libc::rlim_t), String> {
+        spec.parse::<Rlimit>().map(|x| (x.soft, x.hard))
+    }
+
+    #[test]
+    fn parses_limits() {
+        assert_eq!(parse("nofile=64"), Ok((64, 64)));
+        assert_eq!(parse("nofile=64:1024"), Ok((64, 1024)));
+        assert_eq!(parse("as=1k:2K"), Ok((1 << 10, 2 << 10)));
+        assert_eq!(parse("as=64m:1G"), Ok((64 << 20, 1 << 30)));
+        assert_eq!(parse("core=0:unlimited"), Ok((0, libc::RLIM_INFINITY)));
+        assert_eq!(
+            parse("stack=inf"),
+            Ok((libc::RLIM_INFINITY, libc::RLIM_INFINITY))
+        );
+        assert_eq!(
+            "cpu=1".parse::<Rlimit>().unwrap().resource,
+            libc::RLIMIT_CPU
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_limits() {
+        assert!(parse("nofile").is_err());
+        assert!(parse("nofile=").is_err());
+        assert!(parse("nofile=k").is_err());
+        assert!(parse("nofile=-1").is_err());
+        assert!(parse("nofile=1:2:3").is_err());
+        assert!(parse("nofile=2:1").is_err());
+        assert!(parse("nofile=unlimited:1").is_err());
+        assert!(parse("nofile=1T").is_err());
+        assert!(parse("as=18446744073709551615G").is_err());
+        assert!(parse("files=1")
+            .unwrap_err()
+            .contains("unknown resource limit"));
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for &spec in &["nofile=64:1024", "core=0:unlimited", "as=1M"] {
+            let limit: Rlimit = spec.parse().unwrap();
+            let again: Rlimit = limit.to_string().parse().unwrap();
+            assert_eq!((again.soft, again.hard), (limit.soft, limit.hard));
+            assert_eq!(again.name, limit.name);
+        }
+    }
+}
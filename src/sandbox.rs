@@ -0,0 +1,557 @@
+use crate::payload;
+use crate::seccomp::Action;
+use crate::sys::check;
+use std::ffi::CString;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::prelude::*;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+/// The network namespace only contains a loopback device that is down, so the
+/// inherited client socket is the only way to reach the outside.
+const NAMESPACES: libc::c_int = libc::CLONE_NEWUSER
+    | libc::CLONE_NEWNS
+    | libc::CLONE_NEWPID
+    | libc::CLONE_NEWNET
+    | libc::CLONE_NEWIPC
+    | libc::CLONE_NEWUTS;
+
+/// Mount flags that are locked once a mount is shared with a less privileged
+/// user namespace and thus have to be kept when remounting.
+const LOCKED_FLAGS: &[(libc::c_ulong, libc::c_ulong)] = &[
+    (libc::ST_NOSUID, libc::MS_NOSUID),
+    (libc::ST_NODEV, libc::MS_NODEV),
+    (libc::ST_NOEXEC, libc::MS_NOEXEC),
+    (libc::ST_NOATIME, libc::MS_NOATIME),
+    (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+    (libc::ST_RELATIME, libc::MS_RELATIME),
+];
+
+/// Paths made available inside a root directory unless told otherwise.
+pub const DEFAULT_BINDS: &[&str] = &["/dev/null", "/dev/urandom", "/proc"];
+
+/// A path of the host that is bind-mounted into the root directory.
+#[derive(Clone)]
+pub struct Bind {
+    source: PathBuf,
+    dest: PathBuf,
+}
+
+impl std::str::FromStr for Bind {
+    type Err = String;
+
+    /// Parses `SOURCE[:DEST]`, the destination defaults to the source. Both
+    /// take the escapes of `payload::decode`, `\x3a` for a colon.
+    fn from_str(spec: &str) -> Result<Bind, String> {
+        let (source, dest) = spec.split_once(':').unwrap_or((spec, spec));
+        let source = PathBuf::from(payload::decode(OsStr::new(source))?);
+        let dest = PathBuf::from(payload::decode(OsStr::new(dest))?);
+        if !source.is_absolute() || !dest.is_absolute() {
+            return Err(format!(
+                "invalid bind mount, expected absolute SOURCE[:DEST]: {}",
+                spec
+            ));
+        }
+        Ok(Bind { source, dest })
+    }
+}
+
+/// Escapes a path for the exec wrapper, colons included as they separate the
+/// source and destination of bind mounts.
+fn encode_path(path: &Path) -> String {
+    payload::encode(path.as_os_str()).replace(':', "\\x3a")
+}
+
+#[derive(Clone, Default)]
+pub struct Sandbox {
+    /// Unshares every namespace instead of just what the root directory needs.
+    pub isolate: bool,
+    /// Makes every mount read-only and puts a tmpfs on /tmp.
+    pub readonly: bool,
+    pub rootfs: Option<PathBuf>,
+    pub binds: Vec<Bind>,
+    /// Syscall policy file and what happens to syscalls it does not allow.
+    pub seccomp: Option<(PathBuf, Action)>,
+}
+
+fn cpath(path: &Path) -> std::io::Result<CString> {
+    Ok(CString::new(path.as_os_str().as_bytes())?)
+}
+
+fn mount<P: AsRef<Path>>(
+    source: Option<&Path>,
+    target: P,
+    fstype: Option<&str>,
+    flags: libc::c_ulong,
+    data: Option<&str>,
+) -> std::io::Result<()> {
+    let cstr = |x: Option<&str>| x.map(CString::new).transpose();
+    let (source, target) = (source.map(cpath).transpose()?, cpath(target.as_ref())?);
+    let (fstype, data) = (cstr(fstype)?, cstr(data)?);
+    let ptr = |x: &Option<CString>| x.as_ref().map_or(ptr::null(), |x| x.as_ptr());
+
+    let ret = unsafe {
+        libc::mount(
+            ptr(&source),
+            target.as_ptr(),
+            ptr(&fstype),
+            flags,
+            ptr(&data) as *const libc::c_void,
+        )
+    };
+    check(ret).map(|_| ()).map_err(|e| {
+        std::io::Error::new(
+            e.kind(),
+            format!("failed to mount {}: {}", target.to_string_lossy(), e),
+        )
+    })
+}
+
+/// Mounts a /proc showing the pid namespace we are in.
+fn mount_proc(path: &Path) -> std::io::Result<()> {
+    mount(
+        Some(Path::new("proc")),
+        path,
+        Some("proc"),
+        libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+        None,
+    )
+}
+
+/// Undoes the octal escapes of whitespace and backslashes in mountinfo.
+fn unescape(path: &str) -> String {
+    let mut out = Vec::with_capacity(path.len());
+    let mut bytes = path.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            out.push(b);
+            continue;
+        }
+        let digits: Vec<u8> = bytes.by_ref().take(3).collect();
+        match std::str::from_utf8(&digits)
+            .ok()
+            .and_then(|x| u8::from_str_radix(x, 8).ok())
+        {
+            Some(c) => out.push(c),
+            None => out.extend_from_slice(&digits),
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Remounts every mount of the current mount namespace read-only, except for
+/// a fresh /proc at `proc`.
+fn remount_readonly(proc: Option<&Path>) -> std::io::Result<()> {
+    let mut mountinfo = String::new();
+    File::open("/proc/self/mountinfo")
+        .and_then(|mut x| x.read_to_string(&mut mountinfo))
+        .map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!("read-only sandboxes need /proc to find all mounts: {}", e),
+            )
+        })?;
+
+    for line in mountinfo.lines() {
+        let path = match line.split(' ').nth(4) {
+            Some(path) => unescape(path),
+            None => continue,
+        };
+        if proc == Some(Path::new(&path)) {
+            continue;
+        }
+
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        let cpath = cpath(Path::new(&path))?;
+        if unsafe { libc::statvfs(cpath.as_ptr(), &mut stat) } < 0 {
+            // Mounts hidden below other mounts cannot be reached anymore.
+            continue;
+        }
+        let flags = LOCKED_FLAGS
+            .iter()
+            .filter(|x| stat.f_flag & x.0 != 0)
+            .fold(0, |flags, x| flags | x.1);
+
+        let remount = libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY | flags;
+        match mount(None, &path, None, remount, None) {
+            Err(e) if path == "/" => return Err(e),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Puts a tmpfs on top of `dir` holding bind mounts of everything `dir`
+/// contains, so that new entries end up in memory instead of on the host.
+fn shadow(dir: &Path) -> std::io::Result<()> {
+    let original = File::open(dir)?;
+    // Still reachable through the descriptor once the tmpfs covers it.
+    let hidden = PathBuf::from(format!("/proc/self/fd/{}", original.as_raw_fd()));
+    let mode = original.metadata()?.permissions().mode() & 0o7777;
+    let entries = std::fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+
+    mount(
+        Some(Path::new("tmpfs")),
+        dir,
+        Some("tmpfs"),
+        libc::MS_NOSUID | libc::MS_NODEV,
+        Some(&format!("mode={:o}", mode)),
+    )?;
+    for entry in entries {
+        let (source, dest) = (hidden.join(entry.file_name()), dir.join(entry.file_name()));
+        let kind = entry.file_type()?;
+        if kind.is_symlink() {
+            std::os::unix::fs::symlink(std::fs::read_link(&source)?, &dest)?;
+            continue;
+        }
+        if kind.is_dir() {
+            std::fs::create_dir(&dest)?;
+        } else {
+            File::create(&dest)?;
+        }
+        mount(
+            Some(&source),
+            &dest,
+            None,
+            libc::MS_BIND | libc::MS_REC,
+            None,
+        )?;
+    }
+    Ok(())
+}
+
+/// Makes sure there is a file or directory at `dest` to mount `source` onto,
+/// without changing the root directory on the host. Missing parts are created
+/// on a tmpfs shadowing the closest existing parent, `ours` keeps track of the
+/// directories that are in memory already.
+fn create_mount_point(source: &Path, dest: &Path, ours: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    if dest.exists() {
+        return Ok(());
+    }
+    // The root directory itself exists, so there always is one.
+    let parent = dest.ancestors().skip(1).find(|x| x.exists()).unwrap();
+    if !ours.iter().any(|x| x == parent) {
+        shadow(parent)?;
+        ours.push(parent.to_path_buf());
+    }
+
+    let missing = dest.ancestors().take_while(|x| *x != parent);
+    let mut missing: Vec<&Path> = missing.skip(1).collect();
+    missing.reverse();
+    for dir in missing {
+        std::fs::create_dir(dir)?;
+        ours.push(dir.to_path_buf());
+    }
+    if source.is_dir() {
+        std::fs::create_dir(dest)?;
+        ours.push(dest.to_path_buf());
+    } else {
+        File::create(dest)?;
+    }
+    Ok(())
+}
+
+/// Joins an absolute path below `root`.
+fn below(root: &Path, path: &Path) -> PathBuf {
+    root.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+fn fork() -> std::io::Result<libc::pid_t> {
+    check(unsafe { libc::fork() })
+}
+
+/// Waits for `pid`, retrying when interrupted by a signal.
+fn wait(pid: libc::pid_t) -> std::io::Result<(libc::pid_t, libc::c_int)> {
+    let mut status = 0;
+    loop {
+        match check(unsafe { libc::waitpid(pid, &mut status, 0) }) {
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            result => return result.map(|pid| (pid, status)),
+        }
+    }
+}
+
+/// Exits the current process the same way a process with `status` did.
+fn exit_with(status: libc::c_int) -> ! {
+    unsafe {
+        if libc::WIFSIGNALED(status) {
+            let signal = libc::WTERMSIG(status);
+            libc::signal(signal, libc::SIG_DFL);
+            libc::raise(signal);
+            libc::_exit(128 + signal);
+        }
+        libc::_exit(libc::WEXITSTATUS(status));
+    }
+}
+
+impl Sandbox {
+    /// Arguments that make the `exec-wrapper` subcommand recreate the sandbox.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.readonly {
+            args.push("--sandbox-readonly".to_string());
+        } else if self.isolate {
+            args.push("--sandbox".to_string());
+        }
+        if let Some(rootfs) = &self.rootfs {
+            args.push(format!("--rootfs={}", encode_path(rootfs)));
+            if self.binds.is_empty() {
+                args.push("--rootfs-bind=".to_string());
+            }
+            for bind in &self.binds {
+                args.push(format!(
+                    "--rootfs-bind={}:{}",
+                    encode_path(&bind.source),
+                    encode_path(&bind.dest)
+                ));
+            }
+        }
+        if let Some((policy, action)) = &self.seccomp {
+            args.push(format!("--seccomp={}", encode_path(policy)));
+            args.push(format!("--seccomp-action={}", action));
+        }
+        args
+    }
+
+    /// The part of the sandbox that gdb can run in. It cannot follow its
+    /// inferior into a new pid namespace and has to stay on the host's
+    /// filesystem to find itself.
+    pub fn for_gdb(&self) -> Option<Sandbox> {
+        if !self.isolate {
+            return None;
+        }
+        Some(Sandbox {
+            rootfs: None,
+            binds: Vec::new(),
+            seccomp: None,
+            ..self.clone()
+        })
+    }
+
+    /// The part of the sandbox left for the inferior of a sandboxed gdb.
+    pub fn for_inferior(&self) -> Option<Sandbox> {
+        if self.rootfs.is_none() && self.seccomp.is_none() {
+            return None;
+        }
+        Some(Sandbox {
+            isolate: false,
+            readonly: false,
+            ..self.clone()
+        })
+    }
+
+    /// Makes a program of the host available at the same path inside the
+    /// root directory, for running the target with it.
+    pub fn bind_program(&mut self, program: &Path) {
+        if self.rootfs.is_some() {
+            self.binds.push(Bind {
+                source: program.to_path_buf(),
+                dest: program.to_path_buf(),
+            });
+        }
+    }
+
+    /// Where `path` ends up once inside the root directory, paths of the host
+    /// that point into it are translated as well.
+    pub fn resolve(&self, path: &Path) -> PathBuf {
+        match &self.rootfs {
+            Some(rootfs) => match path.strip_prefix(rootfs) {
+                Ok(inner) => Path::new("/").join(inner),
+                Err(_) => path.to_path_buf(),
+            },
+            None => path.to_path_buf(),
+        }
+    }
+
+    /// Where a path inside the root directory is found on the host.
+    pub fn host_path(&self, path: &Path) -> PathBuf {
+        match &self.rootfs {
+            Some(rootfs) => below(rootfs, path),
+            None => path.to_path_buf(),
+        }
+    }
+
+    /// Moves into new namespaces and only returns in a process that is ready
+    /// to exec the sandboxed command.
+    ///
+    /// Entering a pid namespace takes a fork, so the process tree ends up as
+    /// the calling process waiting for an init process, which in turn waits for
+    /// the command. The calling process exits with the command's status and
+    /// once init exits the kernel kills everything left in the sandbox.
+    ///
+    /// Without isolation only a mount namespace is needed to change the root
+    /// directory, plus a user namespace when not running as root. Nothing has
+    /// to be forked then.
+    pub fn enter(&self) -> std::io::Result<()> {
+        if !self.isolate && self.rootfs.is_none() {
+            return Ok(());
+        }
+
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let namespaces = match (self.isolate, uid) {
+            (true, _) => NAMESPACES,
+            (false, 0) => libc::CLONE_NEWNS,
+            (false, _) => libc::CLONE_NEWUSER | libc::CLONE_NEWNS,
+        };
+        check(unsafe { libc::unshare(namespaces) })?;
+
+        if namespaces & libc::CLONE_NEWUSER != 0 {
+            // Unprivileged users may only map their own ids, and only after
+            // giving up on setgroups.
+            std::fs::write("/proc/self/setgroups", "deny")?;
+            std::fs::write("/proc/self/uid_map", format!("{} {} 1\n", uid, uid))?;
+            std::fs::write("/proc/self/gid_map", format!("{} {} 1\n", gid, gid))?;
+        }
+        if !self.isolate {
+            return self.mount_all();
+        }
+
+        let mut fds = [0; 2];
+        check(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) })?;
+        let (mut status_read, status_write) =
+            unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+        let init = fork()?;
+        if init == 0 {
+            drop(status_read);
+            return self.init(status_write);
+        }
+        drop(status_write);
+
+        // Keys typed into a terminal are meant for the command, which also
+        // receives them as part of the same process group.
+        unsafe {
+            libc::signal(libc::SIGINT, libc::SIG_IGN);
+            libc::signal(libc::SIGQUIT, libc::SIG_IGN);
+        }
+
+        let (_, status) = wait(init)?;
+        let mut buf = [0; 4];
+        match status_read.read_exact(&mut buf) {
+            Ok(()) => exit_with(i32::from_ne_bytes(buf)),
+            Err(_) => exit_with(status),
+        }
+    }
+
+    /// Runs as pid 1 of the new pid namespace. Sets up the mounts, forks the
+    /// command and reports its wait status through `status`.
+    fn init(&self, mut status: File) -> std::io::Result<()> {
+        unsafe {
+            libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+        }
+
+        self.mount_all()?;
+
+        let command = fork()?;
+        if command == 0 {
+            return Ok(());
+        }
+
+        // Orphans get reparented to us, so reap them too until the command
+        // itself is gone.
+        loop {
+            match wait(-1) {
+                Ok((pid, wait_status)) if pid == command => {
+                    let _ = status.write_all(&wait_status.to_ne_bytes());
+                    unsafe { libc::_exit(0) };
+                }
+                Ok(_) => {}
+                Err(_) => unsafe { libc::_exit(1) },
+            }
+        }
+    }
+
+    /// Whether /proc gets a fresh instance for our pid namespace instead of
+    /// being bound from the host.
+    fn is_fresh_proc(&self, bind: &Bind) -> bool {
+        self.isolate && bind.source == Path::new("/proc")
+    }
+
+    /// Sets up the mount namespace we just entered.
+    fn mount_all(&self) -> std::io::Result<()> {
+        // Nothing done in here should ever be visible outside the sandbox.
+        mount(None, "/", None, libc::MS_REC | libc::MS_PRIVATE, None)?;
+
+        let proc = match &self.rootfs {
+            Some(rootfs) => self.pivot(rootfs)?,
+            None if self.isolate => {
+                mount_proc(Path::new("/proc"))?;
+                Some(PathBuf::from("/proc"))
+            }
+            None => None,
+        };
+
+        if self.readonly {
+            remount_readonly(proc.as_deref())?;
+            mount(
+                Some(Path::new("tmpfs")),
+                "/tmp",
+                Some("tmpfs"),
+                libc::MS_NOSUID | libc::MS_NODEV,
+                Some("mode=1777"),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Makes `rootfs` the root directory, taking the bind mounts along, and
+    /// returns where a fresh /proc got mounted.
+    fn pivot(&self, rootfs: &Path) -> std::io::Result<Option<PathBuf>> {
+        // pivot_root only accepts mount points as the new root.
+        mount(
+            Some(rootfs),
+            rootfs,
+            None,
+            libc::MS_BIND | libc::MS_REC,
+            None,
+        )?;
+
+        let mut ours = Vec::new();
+        let mut create_mount_point = |source: &Path, dest: &Path| {
+            create_mount_point(source, dest, &mut ours).map_err(|e| {
+                std::io::Error::new(
+                    e.kind(),
+                    format!("failed to create {}: {}", dest.display(), e),
+                )
+            })
+        };
+        if self.readonly {
+            // For the tmpfs put on it later on.
+            create_mount_point(Path::new("/tmp"), &below(rootfs, Path::new("/tmp")))?;
+        }
+
+        let mut proc = None;
+        for bind in &self.binds {
+            let dest = below(rootfs, &bind.dest);
+            create_mount_point(&bind.source, &dest)?;
+            if self.is_fresh_proc(bind) {
+                // The kernel only hands out new instances of /proc while the
+                // old one is still around.
+                mount_proc(&dest)?;
+                proc = Some(bind.dest.clone());
+            } else {
+                let flags = libc::MS_BIND | libc::MS_REC;
+                mount(Some(&bind.source), dest, None, flags, None)?;
+            }
+        }
+
+        // Stacking the old root below the new one and detaching it right away
+        // saves us from needing a directory to put it in.
+        std::env::set_current_dir(rootfs)?;
+        let dot = CString::new(".")?;
+        check(
+            unsafe { libc::syscall(libc::SYS_pivot_root, dot.as_ptr(), dot.as_ptr()) }
+                as libc::c_int,
+        )?;
+        check(unsafe { libc::umount2(dot.as_ptr(), libc::MNT_DETACH) })?;
+        std::env::set_current_dir("/")?;
+
+        Ok(proc)
+    }
+}
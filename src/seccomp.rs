@@ -0,0 +1,569 @@
+use crate::syscalls;
+use std::ffi::{CString, OsString};
+use std::fmt;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::Path;
+
+const AUDIT_ARCH_X86_64: u32 = 0xc000_003e;
+const AUDIT_ARCH_I386: u32 = 0x4000_0003;
+/// Set in the numbers of x32 syscalls, which amd64 processes can make too.
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_LOG: u32 = 0x7ffc_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JMP_JA: u16 = 0x05;
+const BPF_JMP_JEQ_K: u16 = 0x15;
+const BPF_JMP_JGE_K: u16 = 0x35;
+const BPF_RET_K: u16 = 0x06;
+
+/// Offsets into `struct seccomp_data`.
+const DATA_NR: u32 = 0;
+const DATA_ARCH: u32 = 4;
+const DATA_ARGS: u32 = 16;
+
+#[repr(C)]
+struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+#[repr(C)]
+struct SockFprog {
+    len: libc::c_ushort,
+    filter: *const SockFilter,
+}
+
+fn stmt(code: u16, k: u32) -> SockFilter {
+    SockFilter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> SockFilter {
+    SockFilter { code, jt, jf, k }
+}
+
+struct Arch {
+    name: &'static str,
+    audit: u32,
+    syscalls: &'static [(&'static str, u32)],
+}
+
+/// Both ways into the kernel an x86 process has, an amd64 process can still
+/// make i386 syscalls through `int $0x80`.
+const ARCHES: [Arch; 2] = [
+    Arch {
+        name: "amd64",
+        audit: AUDIT_ARCH_X86_64,
+        syscalls: syscalls::AMD64,
+    },
+    Arch {
+        name: "x86",
+        audit: AUDIT_ARCH_I386,
+        syscalls: syscalls::X86,
+    },
+];
+
+/// The architecture netpwn itself makes syscalls with.
+#[cfg(target_arch = "x86_64")]
+const NATIVE_ARCH: Option<usize> = Some(0);
+#[cfg(target_arch = "x86")]
+const NATIVE_ARCH: Option<usize> = Some(1);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "x86")))]
+const NATIVE_ARCH: Option<usize> = None;
+
+/// What happens to syscalls the policy does not allow.
+#[derive(Clone, Copy)]
+pub enum Action {
+    Kill,
+    Errno(u16),
+    Log,
+}
+
+impl std::str::FromStr for Action {
+    type Err = String;
+
+    /// Parses `kill`, `log` or `errno[:N]`, the errno defaults to EPERM.
+    fn from_str(action: &str) -> Result<Action, String> {
+        match action.split_once(':') {
+            None if action == "kill" => Ok(Action::Kill),
+            None if action == "log" => Ok(Action::Log),
+            None if action == "errno" => Ok(Action::Errno(libc::EPERM as u16)),
+            Some(("errno", errno)) => match errno.parse() {
+                Ok(errno) if errno < 4096 => Ok(Action::Errno(errno)),
+                _ => Err(format!("invalid errno: {}", errno)),
+            },
+            _ => Err(format!(
+                "invalid seccomp action, expected kill, log or errno[:N]: {}",
+                action
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::Kill => write!(f, "kill"),
+            Action::Errno(errno) => write!(f, "errno:{}", errno),
+            Action::Log => write!(f, "log"),
+        }
+    }
+}
+
+impl Action {
+    fn ret(self) -> u32 {
+        match self {
+            Action::Kill => SECCOMP_RET_KILL_PROCESS,
+            Action::Errno(errno) => SECCOMP_RET_ERRNO | errno as u32,
+            Action::Log => SECCOMP_RET_LOG,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Rules {
+    allow: Vec<u32>,
+    deny: Vec<u32>,
+}
+
+/// A syscall filter read from a policy file like
+///
+/// ```text
+/// # lines before any section apply to both architectures
+/// allow read write exit exit_group
+/// deny execve execveat
+/// [x86]
+/// allow socketcall
+/// ```
+///
+/// As soon as anything is allowed, everything else is denied. Otherwise only
+/// the denied syscalls are.
+///
+/// The filter is installed before the target is executed, so it has to let
+/// that execve through. It does so by the address of the path, which means a
+/// target passing a path at the same address gets to execute it as well.
+pub struct Policy {
+    rules: [Rules; 2],
+    allowlist: bool,
+    action: Action,
+}
+
+impl Policy {
+    pub fn load(path: &Path, action: Action) -> std::io::Result<Policy> {
+        let invalid = |line: usize, error: String| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{}:{}: {}", path.display(), line, error),
+            )
+        };
+
+        let mut policy = Policy {
+            rules: Default::default(),
+            allowlist: false,
+            action,
+        };
+        let mut arches = vec![0, 1];
+        for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(section) = line.strip_prefix('[').and_then(|x| x.strip_suffix(']')) {
+                arches = match ARCHES.iter().position(|x| x.name == section) {
+                    Some(arch) => vec![arch],
+                    None => {
+                        return Err(invalid(i + 1, format!("unknown architecture: {}", section)))
+                    }
+                };
+                continue;
+            }
+
+            let mut words = line.split_whitespace();
+            let allow = match words.next() {
+                Some("allow") => true,
+                Some("deny") => false,
+                Some(word) => {
+                    return Err(invalid(i + 1, format!("expected allow or deny: {}", word)))
+                }
+                None => continue,
+            };
+            policy.allowlist |= allow;
+
+            for name in words {
+                let mut known = false;
+                for &arch in &arches {
+                    let nr = match ARCHES[arch].syscalls.iter().find(|x| x.0 == name) {
+                        Some(&(_, nr)) => nr,
+                        None => continue,
+                    };
+                    known = true;
+                    let rules = &mut policy.rules[arch];
+                    if allow {
+                        rules.allow.push(nr);
+                    } else {
+                        rules.deny.push(nr);
+                    }
+                }
+                if !known {
+                    return Err(invalid(i + 1, format!("unknown syscall: {}", name)));
+                }
+            }
+        }
+
+        Ok(policy)
+    }
+
+    /// Whether the policy gets in the way of the syscall `name`, on any
+    /// architecture that has it.
+    pub fn blocks(&self, name: &str) -> bool {
+        if let Action::Log = self.action {
+            return false;
+        }
+        ARCHES.iter().zip(&self.rules).any(|(arch, rules)| {
+            match arch.syscalls.iter().find(|x| x.0 == name) {
+                Some((_, nr)) => {
+                    rules.deny.contains(nr) || self.allowlist && !rules.allow.contains(nr)
+                }
+                None => false,
+            }
+        })
+    }
+
+    /// Compiles the policy, additionally letting through an execve of the
+    /// path at address `exec` so that we can still start the target.
+    fn filter(&self, native: usize, exec: usize) -> Vec<SockFilter> {
+        let denied = self.action.ret();
+        let mut filter = vec![stmt(BPF_LD_W_ABS, DATA_ARCH)];
+        let mut blocks = Vec::new();
+
+        for (i, arch) in ARCHES.iter().enumerate() {
+            let rules = &self.rules[i];
+            let mut block = vec![stmt(BPF_LD_W_ABS, DATA_NR)];
+
+            if i == native {
+                let execve = arch.syscalls.iter().find(|x| x.0 == "execve").unwrap().1;
+                let exec = exec as u64;
+                block.extend(vec![
+                    jump(BPF_JMP_JEQ_K, execve, 0, 5),
+                    stmt(BPF_LD_W_ABS, DATA_ARGS),
+                    jump(BPF_JMP_JEQ_K, exec as u32, 0, 3),
+                    stmt(BPF_LD_W_ABS, DATA_ARGS + 4),
+                    jump(BPF_JMP_JEQ_K, (exec >> 32) as u32, 0, 1),
+                    stmt(BPF_RET_K, SECCOMP_RET_ALLOW),
+                    stmt(BPF_LD_W_ABS, DATA_NR),
+                ]);
+            }
+            if arch.audit == AUDIT_ARCH_X86_64 {
+                block.push(jump(BPF_JMP_JGE_K, X32_SYSCALL_BIT, 0, 1));
+                block.push(stmt(BPF_RET_K, denied));
+            }
+
+            let rets = rules.deny.iter().map(|&nr| (nr, denied));
+            let rets = rets.chain(rules.allow.iter().map(|&nr| (nr, SECCOMP_RET_ALLOW)));
+            for (nr, ret) in rets {
+                block.push(jump(BPF_JMP_JEQ_K, nr, 0, 1));
+                block.push(stmt(BPF_RET_K, ret));
+            }
+            block.push(stmt(
+                BPF_RET_K,
+                if self.allowlist {
+                    denied
+                } else {
+                    SECCOMP_RET_ALLOW
+                },
+            ));
+
+            blocks.push((arch.audit, block));
+        }
+
+        // A dispatch on the architecture up front, every block can be longer
+        // than what conditional jumps reach.
+        let mut offset = 2 * blocks.len() as u32 + 1;
+        for (i, (audit, block)) in blocks.iter().enumerate() {
+            filter.push(jump(BPF_JMP_JEQ_K, *audit, 0, 1));
+            filter.push(stmt(BPF_JMP_JA, offset - 2 * i as u32 - 2));
+            offset += block.len() as u32;
+        }
+        filter.push(stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS));
+        for (_, block) in blocks {
+            filter.extend(block);
+        }
+
+        filter
+    }
+
+    /// Installs the filter and executes `program`, only returning on failure.
+    pub fn exec(&self, program: &Path, args: &[OsString]) -> std::io::Error {
+        let native = match NATIVE_ARCH {
+            Some(native) => native,
+            None => {
+                return std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "seccomp policies are only supported on x86 and amd64",
+                )
+            }
+        };
+
+        // execve is made by hand rather than through std so that the filter
+        // can recognize it by the address of the path.
+        let program = match which::which(program) {
+            Ok(program) => program,
+            Err(_) => return std::io::Error::from_raw_os_error(libc::ENOENT),
+        };
+        let cstring = |x: Vec<u8>| CString::new(x).map_err(std::io::Error::from);
+        let path = match cstring(program.into_os_string().into_vec()) {
+            Ok(path) => path,
+            Err(e) => return e,
+        };
+        let argv: std::io::Result<Vec<CString>> = args
+            .iter()
+            .map(|x| cstring(x.as_bytes().to_vec()))
+            .collect();
+        let envp: std::io::Result<Vec<CString>> = std::env::vars_os()
+            .map(|(key, value)| {
+                let mut var = key.into_vec();
+                var.push(b'=');
+                var.extend(value.into_vec());
+                cstring(var)
+            })
+            .collect();
+        let (argv, envp) = match (argv, envp) {
+            (Ok(argv), Ok(envp)) => (argv, envp),
+            (Err(e), _) | (_, Err(e)) => return e,
+        };
+        let pointers = |x: &[CString]| {
+            let mut pointers: Vec<*const libc::c_char> = x.iter().map(|x| x.as_ptr()).collect();
+            pointers.push(std::ptr::null());
+            pointers
+        };
+        let (argv, envp) = (pointers(&argv), pointers(&envp));
+
+        let filter = self.filter(native, path.as_ptr() as usize);
+        let prog = SockFprog {
+            len: filter.len() as libc::c_ushort,
+            filter: filter.as_ptr(),
+        };
+        unsafe {
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) < 0
+                || libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &prog) < 0
+            {
+                return std::io::Error::last_os_error();
+            }
+            libc::execve(path.as_ptr(), argv.as_ptr(), envp.as_ptr());
+        }
+
+        std::io::Error::last_os_error()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXEC: usize = 0x1234_5678_9abc_def0;
+
+    fn load(name: &str, text: &str, action: Action) -> std::io::Result<Policy> {
+        let path =
+            std::env::temp_dir().join(format!("netpwn-seccomp-{}-{}", std::process::id(), name));
+        std::fs::write(&path, text).unwrap();
+        let policy = Policy::load(&path, action);
+        std::fs::remove_file(&path).unwrap();
+        policy
+    }
+
+    fn error(name: &str, text: &str) -> String {
+        match load(name, text, Action::Kill) {
+            Ok(_) => panic!("loaded {}", name),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    fn nr(arch: usize, name: &str) -> u32 {
+        ARCHES[arch]
+            .syscalls
+            .iter()
+            .find(|x| x.0 == name)
+            .unwrap()
+            .1
+    }
+
+    /// Runs the filter the way the kernel would for a syscall with the given
+    /// first argument.
+    fn run(filter: &[SockFilter], audit: u32, nr: u32, arg: u64) -> u32 {
+        let mut acc = 0;
+        let mut pc = 0;
+        loop {
+            let insn = &filter[pc];
+            pc += 1;
+            match insn.code {
+                BPF_LD_W_ABS => {
+                    acc = match insn.k {
+                        DATA_NR => nr,
+                        DATA_ARCH => audit,
+                        DATA_ARGS => arg as u32,
+                        k if k == DATA_ARGS + 4 => (arg >> 32) as u32,
+                        k => panic!("load from offset {}", k),
+                    }
+                }
+                BPF_JMP_JA => pc += insn.k as usize,
+                BPF_JMP_JEQ_K | BPF_JMP_JGE_K => {
+                    let taken = match insn.code {
+                        BPF_JMP_JEQ_K => acc == insn.k,
+                        _ => acc >= insn.k,
+                    };
+                    pc += if taken { insn.jt } else { insn.jf } as usize;
+                }
+                BPF_RET_K => return insn.k,
+                code => panic!("unknown instruction {:#x}", code),
+            }
+        }
+    }
+
+    #[test]
+    fn load_reports_errors_with_line() {
+        let e = error("syscall", "allow read\nallow frobnicate\n");
+        assert!(e.ends_with(":2: unknown syscall: frobnicate"), "{}", e);
+        let e = error("section", "# comment\n[arm]\nallow read\n");
+        assert!(e.ends_with(":2: unknown architecture: arm"), "{}", e);
+        let e = error("keyword", "permit read\n");
+        assert!(e.ends_with(":1: expected allow or deny: permit"), "{}", e);
+        // Only known on x86, so not in an amd64 section either.
+        let e = error("arch", "[amd64]\nallow socketcall\n");
+        assert!(e.ends_with(":2: unknown syscall: socketcall"), "{}", e);
+    }
+
+    #[test]
+    fn blocks_denied_and_unlisted_syscalls() {
+        let policy = load("deny", "deny execve # comment\n", Action::Kill).unwrap();
+        assert!(policy.blocks("execve"));
+        assert!(!policy.blocks("read"));
+
+        let policy = load(
+            "allow",
+            "allow read write\n[x86]\nallow socketcall\n",
+            Action::Kill,
+        );
+        let policy = policy.unwrap();
+        assert!(!policy.blocks("read"));
+        assert!(!policy.blocks("socketcall"));
+        assert!(policy.blocks("execve"));
+        assert!(policy.blocks("open"));
+
+        let policy = load("log", "deny execve\n", Action::Log).unwrap();
+        assert!(!policy.blocks("execve"));
+    }
+
+    #[test]
+    fn filter_dispatches_on_architecture() {
+        let policy = load("dispatch", "deny read\n", Action::Kill).unwrap();
+        let filter = policy.filter(0, EXEC);
+
+        assert_eq!((filter[0].code, filter[0].k), (BPF_LD_W_ABS, DATA_ARCH));
+        for (i, arch) in ARCHES.iter().enumerate() {
+            let check = &filter[1 + 2 * i];
+            assert_eq!((check.code, check.k), (BPF_JMP_JEQ_K, arch.audit));
+            assert_eq!((check.jt, check.jf), (0, 1));
+        }
+        assert_eq!(
+            (filter[5].code, filter[5].k),
+            (BPF_RET_K, SECCOMP_RET_KILL_PROCESS)
+        );
+
+        // Both jumps land on the start of their block.
+        let block = |i: usize| {
+            let ja = &filter[2 + 2 * i];
+            assert_eq!(ja.code, BPF_JMP_JA);
+            3 + 2 * i + ja.k as usize
+        };
+        assert_eq!(block(0), 6);
+        let second = block(1);
+        assert!(second > 6);
+        for start in [6, second].iter() {
+            assert_eq!(
+                (filter[*start].code, filter[*start].k),
+                (BPF_LD_W_ABS, DATA_NR)
+            );
+        }
+        // The second block follows the first, which ends in a return.
+        assert_eq!(filter[second - 1].code, BPF_RET_K);
+
+        assert_eq!(run(&filter, 0xdead, 0, 0), SECCOMP_RET_KILL_PROCESS);
+    }
+
+    #[test]
+    fn filter_lets_only_the_target_execve_through() {
+        let policy = load("execve", "deny execve\n", Action::Errno(1)).unwrap();
+        let filter = policy.filter(0, EXEC);
+        let execve = nr(0, "execve");
+
+        let check = &filter[7];
+        assert_eq!((check.code, check.k), (BPF_JMP_JEQ_K, execve));
+        let low = &filter[9];
+        assert_eq!((low.code, low.k), (BPF_JMP_JEQ_K, EXEC as u32));
+        let high = &filter[11];
+        assert_eq!((high.code, high.k), (BPF_JMP_JEQ_K, (EXEC >> 32) as u32));
+        // Every mismatch skips ahead to reloading the syscall number.
+        for (insn, at) in [(check, 7), (low, 9), (high, 11)].iter() {
+            let target = at + 1 + insn.jf as usize;
+            assert_eq!(target, 13);
+            assert_eq!(
+                (filter[target].code, filter[target].k),
+                (BPF_LD_W_ABS, DATA_NR)
+            );
+        }
+        assert_eq!([check.jf, low.jf, high.jf], [5, 3, 1]);
+        assert_eq!(filter[12].k, SECCOMP_RET_ALLOW);
+
+        let audit = AUDIT_ARCH_X86_64;
+        assert_eq!(run(&filter, audit, execve, EXEC as u64), SECCOMP_RET_ALLOW);
+        let denied = SECCOMP_RET_ERRNO | 1;
+        assert_eq!(run(&filter, audit, execve, EXEC as u64 + 1), denied);
+        assert_eq!(run(&filter, audit, execve, EXEC as u64 ^ 1 << 32), denied);
+        // Only the native architecture gets the exception.
+        let x86 = nr(1, "execve");
+        assert_eq!(run(&filter, AUDIT_ARCH_I386, x86, EXEC as u64), denied);
+    }
+
+    #[test]
+    fn filter_follows_the_rules() {
+        let text = "allow read exit\n[x86]\nallow socketcall\n";
+        let policy = load("rules", text, Action::Kill).unwrap();
+        let filter = policy.filter(0, EXEC);
+        let (amd64, x86) = (AUDIT_ARCH_X86_64, AUDIT_ARCH_I386);
+
+        assert_eq!(run(&filter, amd64, nr(0, "read"), 0), SECCOMP_RET_ALLOW);
+        assert_eq!(
+            run(&filter, amd64, nr(0, "write"), 0),
+            SECCOMP_RET_KILL_PROCESS
+        );
+        let x32 = X32_SYSCALL_BIT | nr(0, "read");
+        assert_eq!(run(&filter, amd64, x32, 0), SECCOMP_RET_KILL_PROCESS);
+        assert_eq!(run(&filter, x86, nr(1, "read"), 0), SECCOMP_RET_ALLOW);
+        assert_eq!(run(&filter, x86, nr(1, "socketcall"), 0), SECCOMP_RET_ALLOW);
+        assert_eq!(
+            run(&filter, x86, nr(1, "write"), 0),
+            SECCOMP_RET_KILL_PROCESS
+        );
+
+        let policy = load("denylist", "deny write\n", Action::Log).unwrap();
+        let filter = policy.filter(1, EXEC);
+        assert_eq!(run(&filter, amd64, nr(0, "write"), 0), SECCOMP_RET_LOG);
+        assert_eq!(run(&filter, amd64, nr(0, "read"), 0), SECCOMP_RET_ALLOW);
+        assert_eq!(run(&filter, x86, nr(1, "write"), 0), SECCOMP_RET_LOG);
+    }
+}
@@ -0,0 +1,245 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::os::unix::io::AsRawFd;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Pause between fragments, without it the receiver would most likely read
+/// them all at once again.
+const FRAGMENT_GAP: Duration = Duration::from_millis(1);
+
+/// How data travelling in one direction is delayed and cut up.
+#[derive(Clone, Copy, Default)]
+pub struct Shaping {
+    pub latency: Duration,
+    /// Inclusive range of fragment sizes writes get split into.
+    pub fragment: Option<(usize, usize)>,
+    /// Bytes per second.
+    pub bandwidth: Option<u64>,
+    /// Delay between single bytes.
+    pub dribble: Option<Duration>,
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct NetworkConditions {
+    pub client_to_target: Shaping,
+    pub target_to_client: Shaping,
+    pub seed: u64,
+}
+
+/// Which directions a shaping option given on the command line applies to.
+pub struct ShapingOption<T> {
+    pub client_to_target: bool,
+    pub target_to_client: bool,
+    pub value: T,
+}
+
+/// Parses an option of the form `[in:|out:]VALUE` where `in` is data sent by
+/// the client and `out` data sent by the target, both if omitted.
+pub fn parse_option<T, F>(option: &str, parse: F) -> Result<ShapingOption<T>, String>
+where
+    F: Fn(&str) -> Option<T>,
+{
+    let (client_to_target, target_to_client, value) = match option.split_once(':') {
+        Some(("in", value)) => (true, false, value),
+        Some(("out", value)) => (false, true, value),
+        Some(_) => return Err(format!("invalid direction, expected in or out: {}", option)),
+        None => (true, true, option),
+    };
+
+    match parse(value) {
+        Some(value) => Ok(ShapingOption {
+            client_to_target,
+            target_to_client,
+            value,
+        }),
+        None => Err(format!("invalid value: {}", option)),
+    }
+}
+
+pub fn parse_millis(value: &str) -> Option<Duration> {
+    value.parse().ok().map(Duration::from_millis)
+}
+
+pub fn parse_fragment(value: &str) -> Option<(usize, usize)> {
+    let (min, max) = match value.split_once('-') {
+        Some((min, max)) => (min.parse().ok()?, max.parse().ok()?),
+        None => {
+            let size = value.parse().ok()?;
+            (size, size)
+        }
+    };
+
+    if min == 0 || min > max {
+        return None;
+    }
+    Some((min, max))
+}
+
+pub fn parse_bandwidth(value: &str) -> Option<u64> {
+    value.parse().ok().filter(|&x| x > 0)
+}
+
+/// xorshift64*, good enough to pick fragment sizes reproducibly.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng(seed.max(1))
+    }
+
+    fn range(&mut self, min: usize, max: usize) -> usize {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        let n = self.0.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        min + (n % (max - min + 1) as u64) as usize
+    }
+}
+
+/// Delivers data to its destination from a separate thread so that delays in
+/// one direction never hold up the other one.
+pub struct ShapedWriter {
+    sender: Option<Sender<(Instant, Vec<u8>)>>,
+    /// Disconnects once the thread is done.
+    finished: Receiver<()>,
+    thread: JoinHandle<()>,
+}
+
+impl ShapedWriter {
+    pub fn spawn(mut to: File, shaping: Shaping, seed: u64) -> ShapedWriter {
+        let (sender, receiver) = mpsc::channel::<(Instant, Vec<u8>)>();
+        let (finished_sender, finished) = mpsc::channel::<()>();
+
+        let thread = std::thread::spawn(move || {
+            let _finished = finished_sender;
+            let mut rng = Rng::new(seed);
+            let fragment = match shaping.dribble {
+                Some(_) => Some((1, 1)),
+                None => shaping.fragment,
+            };
+
+            for (received, data) in receiver {
+                let due = received + shaping.latency;
+                std::thread::sleep(due.saturating_duration_since(Instant::now()));
+
+                let mut rest = data.as_slice();
+                while !rest.is_empty() {
+                    let size = match fragment {
+                        Some((min, max)) => std::cmp::min(rng.range(min, max), rest.len()),
+                        None => rest.len(),
+                    };
+                    let (chunk, remaining) = rest.split_at(size);
+                    if to.write_all(chunk).is_err() {
+                        return;
+                    }
+                    rest = remaining;
+
+                    let mut delay = match (shaping.dribble, fragment) {
+                        (Some(dribble), _) => dribble,
+                        (None, Some(_)) => FRAGMENT_GAP,
+                        (None, None) => Duration::default(),
+                    };
+                    if let Some(bandwidth) = shaping.bandwidth {
+                        delay += Duration::from_secs_f64(size as f64 / bandwidth as f64);
+                    }
+                    std::thread::sleep(delay);
+                }
+            }
+
+            // All queued data went out, now pass on the end of the stream.
+            unsafe { libc::shutdown(to.as_raw_fd(), libc::SHUT_WR) };
+        });
+
+        ShapedWriter {
+            sender: Some(sender),
+            finished,
+            thread,
+        }
+    }
+
+    /// Queues data for delivery, returns false once the destination is gone.
+    pub fn write(&self, data: &[u8]) -> bool {
+        match &self.sender {
+            Some(sender) => sender.send((Instant::now(), data.to_vec())).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Marks the end of the data, the destination is shut down as soon as
+    /// everything queued before was delivered.
+    pub fn close(&mut self) {
+        self.sender.take();
+    }
+
+    /// Waits for everything queued to be delivered, at most until `deadline`.
+    /// Returns false if it was not, the thread is left to give up on its own
+    /// once writing fails.
+    pub fn join(mut self, deadline: Option<Instant>) -> bool {
+        self.close();
+        let finished = match deadline {
+            Some(deadline) => {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                !matches!(
+                    self.finished.recv_timeout(timeout),
+                    Err(RecvTimeoutError::Timeout)
+                )
+            }
+            None => {
+                let _ = self.finished.recv();
+                true
+            }
+        };
+        if finished {
+            let _ = self.thread.join();
+        }
+        finished
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn directions<T>(option: &ShapingOption<T>) -> (bool, bool) {
+        (option.client_to_target, option.target_to_client)
+    }
+
+    #[test]
+    fn parse_option_picks_directions() {
+        let option = parse_option("in:5", parse_millis).unwrap();
+        assert_eq!(directions(&option), (true, false));
+        assert_eq!(option.value, Duration::from_millis(5));
+
+        let option = parse_option("out:5", parse_millis).unwrap();
+        assert_eq!(directions(&option), (false, true));
+
+        let option = parse_option("5", parse_millis).unwrap();
+        assert_eq!(directions(&option), (true, true));
+    }
+
+    #[test]
+    fn parse_option_rejects_invalid_input() {
+        assert!(parse_option("up:5", parse_millis).is_err());
+        assert!(parse_option(":5", parse_millis).is_err());
+        assert!(parse_option("in:", parse_millis).is_err());
+        assert!(parse_option("in:-5", parse_millis).is_err());
+        assert!(parse_option("in:out:5", parse_millis).is_err());
+        assert!(parse_option("0", parse_bandwidth).is_err());
+    }
+
+    #[test]
+    fn parse_fragment_sizes() {
+        assert_eq!(parse_fragment("1"), Some((1, 1)));
+        assert_eq!(parse_fragment("2-8"), Some((2, 8)));
+        assert_eq!(parse_fragment("4-4"), Some((4, 4)));
+        assert_eq!(parse_fragment("0"), None);
+        assert_eq!(parse_fragment("0-4"), None);
+        assert_eq!(parse_fragment("8-2"), None);
+        assert_eq!(parse_fragment("-4"), None);
+        assert_eq!(parse_fragment("4-"), None);
+        assert_eq!(parse_fragment("1-2-3"), None);
+        assert_eq!(parse_fragment("x"), None);
+    }
+}
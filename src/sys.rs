@@ -0,0 +1,14 @@
+//! Helpers for making system calls directly.
+//!
+//! Code that runs between fork and exec, in `pre_exec` closures or the
+//! sandbox's children, may only make system calls: another thread could have
+//! held the allocator's lock or any other one while we forked. Functions that
+//! are fine to call there say so by pointing here.
+
+/// Turns the -1 libc returns on failure into the error in errno.
+pub fn check(ret: libc::c_int) -> std::io::Result<libc::c_int> {
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(ret)
+}
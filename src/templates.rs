@@ -0,0 +1,283 @@
+//! Code gdb compiles into the target to hand it the client socket as stdio,
+//! `fd` is defined in front of it. Each one duplicates the socket onto 0, 1
+//! and 2 and closes the original.
+
+use crate::elf::{self, TargetInfo};
+
+const AMD64_TEMPLATE: &str = "
+void _gdb_expr(void) {
+	__asm__ (
+		\"movq $33, %%rax\\n\"
+		\"movl %0, %%edi\\n\"
+		\"movq $0, %%rsi\\n\"
+		\"syscall\\n\"
+		\"movq $33, %%rax\\n\"
+		\"movl %0, %%edi\\n\"
+		\"movq $1, %%rsi\\n\"
+		\"syscall\\n\"
+		\"movq $33, %%rax\\n\"
+		\"movl %0, %%edi\\n\"
+		\"movq $2, %%rsi\\n\"
+		\"syscall\\n\"
+		\"movq $3, %%rax\\n\"
+		\"movl %0, %%edi\\n\"
+		\"syscall\\n\"
+		:: \"b\"(fd) : \"%rax\", \"%rdi\", \"%rsi\", \"%rcx\", \"%r11\"
+	);
+}
+";
+
+/// 32-bit processes enter the kernel through `int $0x80`, `syscall` is only
+/// there for 64-bit code, even on a 64-bit kernel.
+const X86_TEMPLATE: &str = "
+void _gdb_expr(void) {
+	__asm__ volatile (
+		\"movl $63, %%eax\\n\"
+		\"movl $0, %%ecx\\n\"
+		\"int $0x80\\n\"
+		\"movl $63, %%eax\\n\"
+		\"movl $1, %%ecx\\n\"
+		\"int $0x80\\n\"
+		\"movl $63, %%eax\\n\"
+		\"movl $2, %%ecx\\n\"
+		\"int $0x80\\n\"
+		\"movl $6, %%eax\\n\"
+		\"int $0x80\\n\"
+		:: \"b\"(fd) : \"%eax\", \"%ecx\", \"memory\"
+	);
+}
+";
+
+/// AArch64 and RISC-V only have dup3, which works the same with no flags.
+const AARCH64_TEMPLATE: &str = "
+void _gdb_expr(void) {
+	for (long i = 0; i < 3; i++) {
+		register long nr __asm__(\"x8\") = 24;
+		register long a0 __asm__(\"x0\") = fd;
+		register long a1 __asm__(\"x1\") = i;
+		register long a2 __asm__(\"x2\") = 0;
+		__asm__ volatile (\"svc #0\" : \"+r\"(a0) : \"r\"(nr), \"r\"(a1), \"r\"(a2) : \"memory\");
+	}
+	register long nr __asm__(\"x8\") = 57;
+	register long a0 __asm__(\"x0\") = fd;
+	__asm__ volatile (\"svc #0\" : \"+r\"(a0) : \"r\"(nr) : \"memory\");
+}
+";
+
+/// r7 holds the syscall number but might be the frame pointer in Thumb code,
+/// so it is saved by hand rather than handed to the compiler.
+const ARM_TEMPLATE: &str = "
+void _gdb_expr(void) {
+	for (long i = 0; i < 3; i++) {
+		register long a0 __asm__(\"r0\") = fd;
+		register long a1 __asm__(\"r1\") = i;
+		__asm__ volatile (
+			\"push {r7}\\n\"
+			\"mov r7, #63\\n\"
+			\"svc #0\\n\"
+			\"pop {r7}\\n\"
+			: \"+r\"(a0) : \"r\"(a1) : \"memory\"
+		);
+	}
+	register long a0 __asm__(\"r0\") = fd;
+	__asm__ volatile (
+		\"push {r7}\\n\"
+		\"mov r7, #6\\n\"
+		\"svc #0\\n\"
+		\"pop {r7}\\n\"
+		: \"+r\"(a0) :: \"memory\"
+	);
+}
+";
+
+const MIPS_O32_TEMPLATE: &str = "
+void _gdb_expr(void) {
+	for (long i = 0; i < 3; i++) {
+		register long nr __asm__(\"$2\") = 4063;
+		register long a0 __asm__(\"$4\") = fd;
+		register long a1 __asm__(\"$5\") = i;
+		register long a3 __asm__(\"$7\");
+		__asm__ volatile (
+			\"syscall\"
+			: \"+r\"(nr), \"=r\"(a3) : \"r\"(a0), \"r\"(a1)
+			: \"$1\", \"$3\", \"$8\", \"$9\", \"$10\", \"$11\", \"$12\", \"$13\", \"$14\", \"$15\", \"$24\", \"$25\", \"hi\", \"lo\", \"memory\"
+		);
+	}
+	register long nr __asm__(\"$2\") = 4006;
+	register long a0 __asm__(\"$4\") = fd;
+	register long a3 __asm__(\"$7\");
+	__asm__ volatile (
+		\"syscall\"
+		: \"+r\"(nr), \"=r\"(a3) : \"r\"(a0)
+		: \"$1\", \"$3\", \"$8\", \"$9\", \"$10\", \"$11\", \"$12\", \"$13\", \"$14\", \"$15\", \"$24\", \"$25\", \"hi\", \"lo\", \"memory\"
+	);
+}
+";
+
+const MIPS_N64_TEMPLATE: &str = "
+void _gdb_expr(void) {
+	for (long i = 0; i < 3; i++) {
+		register long nr __asm__(\"$2\") = 5032;
+		register long a0 __asm__(\"$4\") = fd;
+		register long a1 __asm__(\"$5\") = i;
+		register long a3 __asm__(\"$7\");
+		__asm__ volatile (
+			\"syscall\"
+			: \"+r\"(nr), \"=r\"(a3) : \"r\"(a0), \"r\"(a1)
+			: \"$1\", \"$3\", \"$8\", \"$9\", \"$10\", \"$11\", \"$12\", \"$13\", \"$14\", \"$15\", \"$24\", \"$25\", \"hi\", \"lo\", \"memory\"
+		);
+	}
+	register long nr __asm__(\"$2\") = 5003;
+	register long a0 __asm__(\"$4\") = fd;
+	register long a3 __asm__(\"$7\");
+	__asm__ volatile (
+		\"syscall\"
+		: \"+r\"(nr), \"=r\"(a3) : \"r\"(a0)
+		: \"$1\", \"$3\", \"$8\", \"$9\", \"$10\", \"$11\", \"$12\", \"$13\", \"$14\", \"$15\", \"$24\", \"$25\", \"hi\", \"lo\", \"memory\"
+	);
+}
+";
+
+const RISCV_TEMPLATE: &str = "
+void _gdb_expr(void) {
+	for (long i = 0; i < 3; i++) {
+		register long nr __asm__(\"a7\") = 24;
+		register long a0 __asm__(\"a0\") = fd;
+		register long a1 __asm__(\"a1\") = i;
+		register long a2 __asm__(\"a2\") = 0;
+		__asm__ volatile (\"ecall\" : \"+r\"(a0) : \"r\"(nr), \"r\"(a1), \"r\"(a2) : \"memory\");
+	}
+	register long nr __asm__(\"a7\") = 57;
+	register long a0 __asm__(\"a0\") = fd;
+	__asm__ volatile (\"ecall\" : \"+r\"(a0) : \"r\"(nr) : \"memory\");
+}
+";
+
+/// 32 and 64-bit PowerPC share their syscall numbers.
+const POWERPC_TEMPLATE: &str = "
+void _gdb_expr(void) {
+	for (long i = 0; i < 3; i++) {
+		register long nr __asm__(\"r0\") = 63;
+		register long a0 __asm__(\"r3\") = fd;
+		register long a1 __asm__(\"r4\") = i;
+		__asm__ volatile (
+			\"sc\"
+			: \"+r\"(nr), \"+r\"(a0), \"+r\"(a1)
+			:: \"r5\", \"r6\", \"r7\", \"r8\", \"r9\", \"r10\", \"r11\", \"r12\", \"cr0\", \"ctr\", \"memory\"
+		);
+	}
+	register long nr __asm__(\"r0\") = 6;
+	register long a0 __asm__(\"r3\") = fd;
+	__asm__ volatile (
+		\"sc\"
+		: \"+r\"(nr), \"+r\"(a0)
+		:: \"r4\", \"r5\", \"r6\", \"r7\", \"r8\", \"r9\", \"r10\", \"r11\", \"r12\", \"cr0\", \"ctr\", \"memory\"
+	);
+}
+";
+
+/// Picks the code that redirects stdio for the architecture of the executable.
+pub fn get(info: &TargetInfo) -> Result<&'static str, String> {
+    match (info.machine, info.bits) {
+        (elf::EM_X86_64, 64) => Ok(AMD64_TEMPLATE),
+        (elf::EM_386, 32) => Ok(X86_TEMPLATE),
+        (elf::EM_X86_64, 32) => Err("x32 executables are not supported".to_string()),
+        (elf::EM_AARCH64, 64) => Ok(AARCH64_TEMPLATE),
+        (elf::EM_ARM, 32) => Ok(ARM_TEMPLATE),
+        // n32 executables are 32-bit too, but they are rare enough to ignore.
+        (elf::EM_MIPS, 32) => Ok(MIPS_O32_TEMPLATE),
+        (elf::EM_MIPS, 64) => Ok(MIPS_N64_TEMPLATE),
+        (elf::EM_RISCV, _) => Ok(RISCV_TEMPLATE),
+        (elf::EM_PPC, 32) | (elf::EM_PPC64, 64) => Ok(POWERPC_TEMPLATE),
+        _ => Err(format!(
+            "cannot redirect stdio of {} executables under gdb, try --pty",
+            info
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(machine: u16, bits: u8, big_endian: bool) -> TargetInfo {
+        TargetInfo {
+            bits,
+            big_endian,
+            machine,
+            pie: true,
+            interpreter: None,
+        }
+    }
+
+    #[test]
+    fn picks_the_template_of_the_architecture() {
+        let cases = [
+            (elf::EM_X86_64, 64, false, AMD64_TEMPLATE),
+            (elf::EM_386, 32, false, X86_TEMPLATE),
+            (elf::EM_AARCH64, 64, false, AARCH64_TEMPLATE),
+            (elf::EM_AARCH64, 64, true, AARCH64_TEMPLATE),
+            (elf::EM_ARM, 32, false, ARM_TEMPLATE),
+            (elf::EM_MIPS, 32, true, MIPS_O32_TEMPLATE),
+            (elf::EM_MIPS, 64, false, MIPS_N64_TEMPLATE),
+            (elf::EM_RISCV, 32, false, RISCV_TEMPLATE),
+            (elf::EM_RISCV, 64, false, RISCV_TEMPLATE),
+            (elf::EM_PPC, 32, true, POWERPC_TEMPLATE),
+            (elf::EM_PPC64, 64, false, POWERPC_TEMPLATE),
+            (elf::EM_PPC64, 64, true, POWERPC_TEMPLATE),
+        ];
+        for &(machine, bits, big_endian, template) in &cases {
+            assert_eq!(get(&info(machine, bits, big_endian)), Ok(template));
+        }
+    }
+
+    #[test]
+    fn rejects_x32() {
+        let err = get(&info(elf::EM_X86_64, 32, false)).unwrap_err();
+        assert!(err.contains("x32"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_other_architectures() {
+        let err = get(&info(2, 64, true)).unwrap_err();
+        assert!(err.contains("SPARC") && err.contains("--pty"), "{}", err);
+        assert!(get(&info(elf::EM_386, 64, false)).is_err());
+        assert!(get(&info(elf::EM_PPC, 64, true)).is_err());
+    }
+
+    #[test]
+    fn i386_enters_the_kernel_through_int_0x80() {
+        assert!(X86_TEMPLATE.contains("int $0x80"));
+        assert!(!X86_TEMPLATE.contains("syscall"));
+        assert!(AMD64_TEMPLATE.contains("syscall"));
+    }
+
+    /// gdb gets each template as a single line.
+    #[test]
+    fn templates_work_on_a_single_line() {
+        let templates = [
+            AMD64_TEMPLATE,
+            X86_TEMPLATE,
+            AARCH64_TEMPLATE,
+            ARM_TEMPLATE,
+            MIPS_O32_TEMPLATE,
+            MIPS_N64_TEMPLATE,
+            RISCV_TEMPLATE,
+            POWERPC_TEMPLATE,
+        ];
+        for template in &templates {
+            let line = template.replace('\n', "");
+            assert!(line.contains("void _gdb_expr(void) {"), "{}", line);
+            assert!(line.contains("fd"), "{}", line);
+            assert!(!line.contains("//") && !line.contains("/*"), "{}", line);
+            for (open, close) in [('{', '}'), ('(', ')')].iter() {
+                assert_eq!(
+                    line.matches(*open).count(),
+                    line.matches(*close).count(),
+                    "{}",
+                    line
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//! Runs a 32-bit program under `--gdb` and checks that the injected dup2 calls
+//! hand it the client socket as stdio, both through ptrace and through gdb's
+//! `compile code`. Needs gdb and a compiler able to build i386 binaries, so the
+//! tests only run with `cargo test -- --ignored`.
+
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// Reports whether stdio are sockets, how many other sockets are open and
+/// echoes a line from stdin to stdout and stderr.
+const PROGRAM: &str = r#"
+#include <dirent.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <sys/stat.h>
+#include <unistd.h>
+
+int main(void) {
+	struct stat st;
+	int stdio = 0, others = 0;
+	for (int fd = 0; fd < 3; fd++) {
+		if (fstat(fd, &st) == 0 && S_ISSOCK(st.st_mode)) {
+			stdio++;
+		}
+	}
+
+	DIR *dir = opendir("/proc/self/fd");
+	struct dirent *entry;
+	while (dir && (entry = readdir(dir))) {
+		int fd = atoi(entry->d_name);
+		if (fd > 2 && fstat(fd, &st) == 0 && S_ISSOCK(st.st_mode)) {
+			others++;
+		}
+	}
+
+	char line[64];
+	ssize_t n = read(0, line, sizeof(line));
+	if (n < 0) {
+		n = 0;
+	}
+	dprintf(1, "stdio=%d others=%d stdout=%.*s", stdio, others, (int)n, line);
+	dprintf(2, "stderr=%.*s", (int)n, line);
+	return 0;
+}
+"#;
+
+struct Cleanup {
+    dir: PathBuf,
+    child: Option<Child>,
+}
+
+impl Drop for Cleanup {
+    fn drop(&mut self) {
+        if let Some(child) = &mut self.child {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn succeeds(cmd: &mut Command) -> bool {
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|x| x.success())
+}
+
+/// Builds the test program.
+fn build(dir: &Path) -> PathBuf {
+    let source = dir.join("fds.c");
+    let binary = dir.join("fds");
+    std::fs::write(&source, PROGRAM).unwrap();
+
+    let built = succeeds(
+        Command::new("cc")
+            .args(["-m32", "-g", "-o"])
+            .arg(&binary)
+            .arg(&source),
+    );
+    assert!(built, "cc cannot build i386 binaries");
+    assert!(
+        succeeds(&mut Command::new(&binary)),
+        "i386 binaries cannot be run"
+    );
+    binary
+}
+
+fn wait_for_address(path: &Path) -> String {
+    let start = Instant::now();
+    loop {
+        if let Ok(address) = std::fs::read_to_string(path) {
+            if let Some(address) = address.split_whitespace().next() {
+                return address.to_string();
+            }
+        }
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "netpwn did not start listening"
+        );
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Runs the test program under gdb with netpwn's extra `args` and returns
+/// what the client received.
+fn run(name: &str, args: &[&str]) -> String {
+    assert!(
+        succeeds(Command::new("gdb").args(["-batch", "-ex", "show version"])),
+        "gdb is not installed"
+    );
+
+    let dir = std::env::temp_dir().join(format!("netpwn-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut cleanup = Cleanup {
+        dir: dir.clone(),
+        child: None,
+    };
+    let binary = build(&dir);
+
+    let ready = dir.join("ready");
+    let mut netpwn = Command::new(env!("CARGO_BIN_EXE_netpwn"))
+        .args(["-p", "0", "--ready-file"])
+        .arg(&ready)
+        .arg("--gdb")
+        .args(args)
+        .arg(&binary)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    // gdb reads its commands from our stdin once the target is stopped.
+    netpwn
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"continue\nquit\n")
+        .unwrap();
+    cleanup.child = Some(netpwn);
+
+    let mut client = TcpStream::connect(wait_for_address(&ready)).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(60)))
+        .unwrap();
+    client.write_all(b"ping\n").unwrap();
+    let mut output = String::new();
+    client.read_to_string(&mut output).unwrap();
+    output
+}
+
+fn check(output: &str) {
+    assert!(
+        output.contains("stdio=3 others=0 stdout=ping\n"),
+        "unexpected output: {:?}",
+        output
+    );
+    assert!(
+        output.contains("stderr=ping\n"),
+        "unexpected output: {:?}",
+        output
+    );
+}
+
+#[test]
+#[ignore = "needs gdb and i386 support"]
+fn gdb_redirects_stdio_of_i386_programs() {
+    check(&run("i386", &[]));
+}
+
+/// Makes sure the `int $0x80` template still works, which x86_64 hosts skip
+/// by default.
+#[test]
+#[ignore = "needs gdb and i386 support"]
+fn gdb_compile_code_redirects_stdio_of_i386_programs() {
+    check(&run("i386-compile", &["--gdb-compile"]));
+}